@@ -0,0 +1,291 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{Datelike, NaiveDateTime, Utc};
+
+use crate::cloud::{Cloud, CloudError};
+use crate::cloud_backend::AsyncCloudBackend;
+use crate::virtual_file_system::{Checksum, FileSystemNode, Metadata, VFSError, VFSFile};
+
+/// Формат имени папки снимка
+const SNAPSHOT_NAME_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+
+/// Суффикс имени папки снимка, пока снимок не создан без ошибок
+const INCOMPLETE_SUFFIX: &str = ".incomplete";
+
+/// Сколько снимков хранить: последний снимок каждого из последних N дней, недель и месяцев.
+/// Самый новый полный снимок хранится всегда
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    /// Последние N снимков независимо от даты
+    pub last: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            last: 1,
+            daily: 7,
+            weekly: 4,
+            monthly: 12,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BackupReport {
+    /// Папка VFS созданного снимка
+    pub snapshot: PathBuf,
+    pub uploaded: Vec<PathBuf>,
+    /// Файлы, содержимое которых уже было в облаке
+    pub reused: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, String)>,
+    /// Снимки, удаленные по политике хранения
+    pub pruned: Vec<PathBuf>,
+}
+
+/// Резервная копия `local_dir` в новый снимок внутри папки VFS `backups_root`.
+///
+/// Снимок - папка с датой создания (UTC) в имени. Файл загружается, только если файла
+/// с таким же содержимым еще нет в VFS, иначе снимок ссылается на уже загруженные части.
+/// Пока снимок создается, имя его папки заканчивается на `.incomplete`, и если при создании
+/// были ошибки, суффикс остается. Затем старые снимки удаляются по `retention`, их части
+/// освобождает сборщик мусора.
+pub async fn backup<T: AsyncCloudBackend>(
+    cloud: &Cloud<T>,
+    local_dir: &Path,
+    backups_root: &Path,
+    retention: &RetentionPolicy
+) -> Result<BackupReport, CloudError> {
+
+    let name = Utc::now().format(SNAPSHOT_NAME_FORMAT).to_string();
+    let snapshot = backups_root.join(format!("{}{}", name, INCOMPLETE_SUFFIX));
+
+    // Снимки называются по секундам, второй снимок в ту же секунду смешал бы файлы
+    if cloud.get_folder(&snapshot).is_ok() || cloud.get_folder(&backups_root.join(&name)).is_ok() {
+        return Err(VFSError::FolderAlreadyExists.into());
+    }
+    cloud.create_folder(&snapshot)?;
+
+    let mut uploaded_content = content_index(cloud);
+
+    let mut report = BackupReport {
+        snapshot: snapshot.clone(),
+        ..BackupReport::default()
+    };
+
+    for path in local_files(local_dir, &mut report)? {
+        let virtual_folder = match path.strip_prefix(local_dir).unwrap().parent() {
+            Some(parent) => snapshot.join(parent),
+            None => snapshot.clone(),
+        };
+
+        let result = async {
+            let content = Checksum::of_file(&path)?;
+            cloud.create_folder(&virtual_folder)?;
+
+            if let Some(uploaded_file) = uploaded_content.get(&content.md5) {
+                let mut metadata = Metadata::default();
+                metadata.record_attributes(&fs::metadata(&path)?);
+                metadata.checksums = uploaded_file.metadata.checksums.clone();
+                metadata.content = Some(content);
+
+                cloud.add_file(&virtual_folder, VFSFile {
+                    name: path.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+                    extension: path.extension().unwrap_or_default().to_string_lossy().to_string(),
+                    build_metafile: uploaded_file.build_metafile.clone(),
+                    parts_name: uploaded_file.parts_name.clone(),
+                    metadata,
+                })?;
+
+                return Ok(true);
+            }
+
+            cloud.async_upload_file(&path, &virtual_folder).await?;
+
+            let virtual_path = virtual_folder.join(path.file_stem().unwrap_or_default());
+            uploaded_content.insert(content.md5, cloud.get_file(&virtual_path)?);
+
+            Ok::<bool, CloudError>(false)
+        }.await;
+
+        match result {
+            Ok(true) => report.reused.push(path),
+            Ok(false) => report.uploaded.push(path),
+            Err(e) => report.failed.push((path, format!("{:?}", e))),
+        }
+    }
+
+    // Неполный снимок не должен вытеснить полные
+    if report.failed.is_empty() {
+        cloud.rename_node(&snapshot, &name)?;
+        report.snapshot = backups_root.join(&name);
+    }
+
+    report.pruned = prune(cloud, backups_root, retention)?;
+
+    Ok(report)
+}
+
+/// Полные снимки в папке `backups_root` от новых к старым
+pub fn snapshots<T: AsyncCloudBackend>(cloud: &Cloud<T>, backups_root: &Path) -> Vec<(NaiveDateTime, PathBuf)> {
+    snapshot_folders(cloud, backups_root, "")
+}
+
+/// Снимки, созданные с ошибками или не достроенные, от новых к старым
+pub fn incomplete_snapshots<T: AsyncCloudBackend>(cloud: &Cloud<T>, backups_root: &Path) -> Vec<(NaiveDateTime, PathBuf)> {
+    snapshot_folders(cloud, backups_root, INCOMPLETE_SUFFIX)
+}
+
+fn snapshot_folders<T: AsyncCloudBackend>(cloud: &Cloud<T>, backups_root: &Path, suffix: &str) -> Vec<(NaiveDateTime, PathBuf)> {
+    let mut snapshots = cloud
+        .get_folder(backups_root)
+        .map(|folder| folder.children
+            .into_iter()
+            .filter(|(_, node)| matches!(node, FileSystemNode::Folder(_)))
+            .filter_map(|(name, _)| {
+                let date = NaiveDateTime::parse_from_str(name.strip_suffix(suffix)?, SNAPSHOT_NAME_FORMAT).ok()?;
+                Some((date, backups_root.join(name)))
+            })
+            .collect::<Vec<_>>()
+        )
+        .unwrap_or_default();
+
+    snapshots.sort_by_key(|(date, _)| std::cmp::Reverse(*date));
+    snapshots
+}
+
+/// Удаление снимков, не попадающих под политику хранения.
+///
+/// Политика применяется только к полным снимкам, поэтому самый новый полный снимок
+/// не удаляется. Снимок с ошибками удаляется, только когда после него создан полный снимок
+pub fn prune<T: AsyncCloudBackend>(
+    cloud: &Cloud<T>,
+    backups_root: &Path,
+    retention: &RetentionPolicy
+) -> Result<Vec<PathBuf>, CloudError> {
+
+    let snapshots = snapshots(cloud, backups_root);
+    let dates = snapshots.iter().map(|(date, _)| *date).collect::<Vec<_>>();
+    let keep = snapshots_to_keep(&dates, retention);
+
+    let newest_complete = dates.first().copied();
+
+    let incomplete = incomplete_snapshots(cloud, backups_root)
+        .into_iter()
+        .filter(|(date, _)| newest_complete.is_some_and(|newest_complete| *date < newest_complete));
+
+    let outdated = snapshots
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !keep.contains(index))
+        .map(|(_, snapshot)| snapshot)
+        .chain(incomplete);
+
+    let mut pruned = vec![];
+
+    for (_, path) in outdated {
+        cloud.remove_folder(&path)?;
+        pruned.push(path);
+    }
+
+    Ok(pruned)
+}
+
+/// Период, в который попадает снимок: день, неделя или месяц
+type PeriodOf = fn(&NaiveDateTime) -> (i32, u32);
+
+/// Индексы хранимых снимков, `dates` отсортированы от новых к старым
+pub fn snapshots_to_keep(dates: &[NaiveDateTime], retention: &RetentionPolicy) -> BTreeSet<usize> {
+
+    let periods: [(usize, PeriodOf); 3] = [
+        (retention.daily, |date| (date.year(), date.ordinal())),
+        (retention.weekly, |date| (date.iso_week().year(), date.iso_week().week())),
+        (retention.monthly, |date| (date.year(), date.month())),
+    ];
+
+    let mut keep = BTreeSet::new();
+
+    keep.extend(0..retention.last.max(1).min(dates.len()));
+
+    for (count, period_of) in periods {
+        let mut last_period = None;
+        let mut kept = 0;
+
+        for (index, date) in dates.iter().enumerate() {
+            let period = period_of(date);
+
+            // Первый встреченный снимок периода - самый новый в нем
+            if last_period != Some(period) && kept < count {
+                keep.insert(index);
+                kept += 1;
+            }
+
+            last_period = Some(period);
+        }
+    }
+
+    keep
+}
+
+/// Уже загруженные файлы VFS по md5 содержимого
+fn content_index<T: AsyncCloudBackend>(cloud: &Cloud<T>) -> HashMap<String, VFSFile> {
+    cloud
+        .get_vfs()
+        .nodes()
+        .into_iter()
+        .filter_map(|(_, node)| match node {
+            FileSystemNode::File(file) if file.metadata.symlink.is_none() => file.metadata
+                .content
+                .as_ref()
+                .map(|content| (content.md5.clone(), file.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Все обычные файлы папки, ссылки пропускаются
+fn local_files(local_dir: &Path, report: &mut BackupReport) -> Result<Vec<PathBuf>, CloudError> {
+    let mut files = vec![];
+    let mut dirs = vec![local_dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_dir() => dirs.push(path),
+                Ok(metadata) if metadata.is_file() => files.push(path),
+                Ok(_) => {},
+                Err(e) => report.failed.push((path, e.to_string())),
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+impl Display for BackupReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Снимок: {}", self.snapshot.display())?;
+        writeln!(f, "Загружено файлов: {}", self.uploaded.len())?;
+        writeln!(f, "Без изменений: {}", self.reused.len())?;
+
+        writeln!(f, "Ошибок: {}", self.failed.len())?;
+        for (path, error) in &self.failed {
+            writeln!(f, "  ! {} - {}", path.display(), error)?;
+        }
+
+        for path in &self.pruned {
+            writeln!(f, "Удален старый снимок: {}", path.display())?;
+        }
+
+        Ok(())
+    }
+}
@@ -2,10 +2,23 @@ use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tokio::io::AsyncReadExt;
+use tokio::sync::broadcast::error::RecvError;
 use telegram_drive::cloud::Cloud;
 use telegram_drive::telegram_backend::TelegramBackend;
 use telegram_drive::virtual_file_system::{FSOption, Metadata, VFSFile, VFSFolder, VirtualFileSystem};
 use telegram_drive::cloud_backend::AsyncCloudBackend;
+use telegram_drive::vfs_diff;
+use telegram_drive::recovery;
+use telegram_drive::garbage_collector::{self, GCOptions};
+use telegram_drive::fsck::{self, FsckOptions};
+use telegram_drive::directory_upload::{self, DirectoryUploadOptions, SymlinkPolicy};
+use telegram_drive::folder_download::{self, ConflictPolicy, FolderDownloadOptions};
+use telegram_drive::folder_sync::FolderSync;
+use telegram_drive::backup::{self, RetentionPolicy};
+use telegram_drive::directory_watch::{DeletionPolicy, DirectoryWatcher, WatchOptions};
+use telegram_drive::vfs_store::VfsStoreKind;
+use telegram_drive::transfer_queue::{JobKind, Priority};
+use telegram_drive::rate_limit::{BandwidthSchedule, RateLimit, ScheduleRule};
 use telegram_drive::virtual_file_system::FileSystemNode::{File, Folder};
 use telegram_drive_file::Options;
 use std::io::Read;
@@ -16,11 +29,29 @@ fn main() {
 
     rt.block_on(async {
 
-        let cloud = Cloud::<TelegramBackend>::new();
+        let cloud = match Cloud::<TelegramBackend>::new() {
+            Ok(cloud) => cloud,
+            Err(e) => {
+                println!("Не удалось открыть хранилище VFS: {:?}", e);
+                return;
+            }
+        };
+        cloud.pull_initial_index().await;
+
+        let mut progress = cloud.subscribe_progress();
+        tokio::spawn(async move {
+            loop {
+                match progress.recv().await {
+                    Ok(event) => println!("{}", event),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
         
         let mut input_str;
 
-        loop {
+        'input: loop {
             input_str = String::new();
             std::io::stdin().read_line(&mut input_str).unwrap();
 
@@ -36,11 +67,392 @@ fn main() {
                         .await
                         .unwrap();
                 },
+                "ud" => {
+                    let usage = "ud <локальная папка> <папка VFS> [--include <glob>]... [--exclude <glob>]... [--symlinks follow|skip|link]";
+
+                    let [_, local_dir, virtual_path, ..] = input_options[..] else {
+                        println!("{}", usage);
+                        continue;
+                    };
+
+                    let mut upload_options = DirectoryUploadOptions::default();
+
+                    for (position, option) in input_options.iter().enumerate().skip(3) {
+                        let Some(value) = input_options.get(position + 1) else {
+                            continue;
+                        };
+
+                        match *option {
+                            "--include" => upload_options.include.push(value.to_string()),
+                            "--exclude" => upload_options.exclude.push(value.to_string()),
+                            "--symlinks" => match SymlinkPolicy::from_name(value) {
+                                Some(symlinks) => upload_options.symlinks = symlinks,
+                                None => {
+                                    println!("{}", usage);
+                                    continue 'input;
+                                }
+                            },
+                            _ => {}
+                        }
+                    }
+
+                    let report = directory_upload::upload_directory(
+                        &cloud,
+                        Path::new(local_dir),
+                        Path::new(virtual_path),
+                        &upload_options,
+                        |progress| println!("{}", progress)
+                    ).await.unwrap();
+                    print!("{}", report);
+                },
                 "d" => {
-                    cloud
-                        .async_download_file(Path::new(input_options[1]))
+                    let usage = "d <путь VFS> [папка или файл] [overwrite|skip|rename]";
+
+                    let [_, virtual_path, ..] = input_options[..] else {
+                        println!("{}", usage);
+                        continue;
+                    };
+
+                    let Some(conflicts) = input_options.get(3).map_or(Some(ConflictPolicy::default()), |policy| ConflictPolicy::from_name(policy)) else {
+                        println!("{}", usage);
+                        continue;
+                    };
+
+                    match cloud
+                        .async_download_file(
+                            Path::new(virtual_path),
+                            Path::new(input_options.get(2).unwrap_or(&"./")),
+                            conflicts
+                        )
                         .await
-                        .unwrap();
+                        .unwrap()
+                    {
+                        Some(path) => println!("Файл сохранен: {}", path.display()),
+                        None => println!("Файл уже существует, пропущен")
+                    }
+                },
+                "dd" => {
+                    let usage = "dd <папка VFS> <локальная папка> [overwrite|skip|rename]";
+
+                    let [_, virtual_path, local_dir, ref policy @ ..] = input_options[..] else {
+                        println!("{}", usage);
+                        continue;
+                    };
+
+                    let Some(conflicts) = policy.first().map_or(Some(ConflictPolicy::default()), |policy| ConflictPolicy::from_name(policy)) else {
+                        println!("{}", usage);
+                        continue;
+                    };
+
+                    let download_options = FolderDownloadOptions {
+                        conflicts,
+                        ..FolderDownloadOptions::default()
+                    };
+
+                    let report = folder_download::download_folder(
+                        &cloud,
+                        Path::new(virtual_path),
+                        Path::new(local_dir),
+                        &download_options
+                    ).await.unwrap();
+                    print!("{}", report);
+                },
+                "sync" => {
+                    let [_, local_dir, virtual_path, ..] = input_options[..] else {
+                        println!("sync <локальная папка> <папка VFS> [--dry-run]");
+                        continue;
+                    };
+
+                    let mut folder_sync = FolderSync::new(
+                        &cloud,
+                        Path::new(local_dir),
+                        Path::new(virtual_path)
+                    );
+
+                    let plan = folder_sync.plan().unwrap();
+                    print!("{}", plan);
+
+                    if !input_options.contains(&"--dry-run") && !plan.is_empty() {
+                        let report = folder_sync.apply(&plan).await.unwrap();
+                        print!("{}", report);
+                    }
+                },
+                "backup" => {
+                    let usage = "backup <локальная папка> <папка VFS> [--last N] [--daily N] [--weekly N] [--monthly N]";
+
+                    let [_, local_dir, virtual_path, ..] = input_options[..] else {
+                        println!("{}", usage);
+                        continue;
+                    };
+
+                    let mut retention = RetentionPolicy::default();
+
+                    for (position, option) in input_options.iter().enumerate().skip(3) {
+                        let field = match *option {
+                            "--last" => &mut retention.last,
+                            "--daily" => &mut retention.daily,
+                            "--weekly" => &mut retention.weekly,
+                            "--monthly" => &mut retention.monthly,
+                            _ => continue,
+                        };
+
+                        let Some(count) = input_options.get(position + 1).and_then(|count| count.parse().ok()) else {
+                            println!("{}", usage);
+                            continue 'input;
+                        };
+                        *field = count;
+                    }
+
+                    let report = backup::backup(
+                        &cloud,
+                        Path::new(local_dir),
+                        Path::new(virtual_path),
+                        &retention
+                    ).await.unwrap();
+                    print!("{}", report);
+                },
+                "watch" => {
+                    let usage = "watch <локальная папка> <папка VFS> [--debounce <секунды>] [--deletions mirror|trash|ignore]";
+
+                    let [_, local_dir, virtual_path, ..] = input_options[..] else {
+                        println!("{}", usage);
+                        continue;
+                    };
+
+                    let mut watch_options = WatchOptions::default();
+
+                    for (position, option) in input_options.iter().enumerate().skip(3) {
+                        let value = input_options.get(position + 1);
+
+                        let parsed = match *option {
+                            "--debounce" => value
+                                .and_then(|value| value.parse().ok())
+                                .map(|secs| watch_options.debounce = Duration::from_secs(secs)),
+                            "--deletions" => value
+                                .and_then(|value| DeletionPolicy::from_name(value))
+                                .map(|deletions| watch_options.deletions = deletions),
+                            _ => continue,
+                        };
+
+                        if parsed.is_none() {
+                            println!("{}", usage);
+                            continue 'input;
+                        }
+                    }
+
+                    let watcher = DirectoryWatcher::new(
+                        &cloud,
+                        Path::new(local_dir),
+                        Path::new(virtual_path),
+                        watch_options
+                    );
+
+                    // Отслеживание до Ctrl+C
+                    watcher.run(async { let _ = tokio::signal::ctrl_c().await; }).await.unwrap();
+                },
+                "diff" => {
+                    // diff <старый vfs.json> [новый vfs.json] [--json]
+                    let as_json = input_options.contains(&"--json");
+                    let files = input_options[1..]
+                        .iter()
+                        .filter(|option| **option != "--json")
+                        .collect::<Vec<_>>();
+
+                    let Some(old_path) = files.first() else {
+                        println!("diff <старый vfs.json> [новый vfs.json] [--json]");
+                        continue;
+                    };
+
+                    let read_vfs = |path: &str| -> Result<VirtualFileSystem, String> {
+                        let vfs_file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+                        serde_json::from_reader(std::io::BufReader::new(vfs_file)).map_err(|e| format!("{}: {}", path, e))
+                    };
+
+                    let vfs = match files.get(1) {
+                        Some(new_path) => read_vfs(old_path).and_then(|old_vfs| Ok((old_vfs, read_vfs(new_path)?))),
+                        None => read_vfs(old_path).map(|old_vfs| (old_vfs, cloud.get_vfs()))
+                    };
+
+                    let (old_vfs, new_vfs) = match vfs {
+                        Ok(vfs) => vfs,
+                        Err(e) => {
+                            println!("! {}", e);
+                            continue;
+                        }
+                    };
+
+                    let change_set = vfs_diff::diff(&old_vfs, &new_vfs);
+
+                    if as_json {
+                        println!("{}", serde_json::to_string_pretty(&change_set).unwrap());
+                    } else {
+                        print!("{}", change_set);
+                    }
+                },
+                "migrate" => {
+                    // migrate <json|sled>
+                    match VfsStoreKind::from_name(input_options[1]) {
+                        Some(kind) => {
+                            cloud.migrate_store(kind).unwrap();
+                            println!("VFS перенесена в хранилище {:?}", kind);
+                        },
+                        None => println!("Неизвестное хранилище: {}", input_options[1])
+                    }
+                },
+                "push-index" => {
+                    let version = cloud.push_index().await.unwrap();
+                    println!("Снимок VFS выгружен в облако, версия {}", version);
+                },
+                "pull-index" => {
+                    match cloud.pull_index().await.unwrap() {
+                        Some((version, report)) => {
+                            println!("VFS объединена со снимком из облака, версия {}", version);
+                            print!("{}", report);
+                        },
+                        None => println!("В облаке нет более новой версии VFS")
+                    }
+                },
+                "recover" => {
+                    let report = recovery::recover_vfs(&cloud).await.unwrap();
+                    print!("{}", report);
+                },
+                "gc" => {
+                    // gc [--apply] [--grace <секунды>]
+                    // Без --apply файлы только перечисляются
+                    let mut gc_options = GCOptions {
+                        dry_run: !input_options.contains(&"--apply"),
+                        ..GCOptions::default()
+                    };
+
+                    if let Some(position) = input_options.iter().position(|option| *option == "--grace") {
+                        let Some(grace_secs) = input_options.get(position + 1).and_then(|value| value.parse().ok()) else {
+                            println!("gc [--apply] [--grace <секунды>]");
+                            continue;
+                        };
+                        gc_options.grace_period = Duration::from_secs(grace_secs);
+                    }
+
+                    let report = garbage_collector::collect_garbage(&cloud, &gc_options).await.unwrap();
+                    print!("{}", report);
+                },
+                "fsck" => {
+                    // fsck [--deep] [--repair]
+                    let fsck_options = FsckOptions {
+                        deep: input_options.contains(&"--deep"),
+                        repair: input_options.contains(&"--repair"),
+                    };
+
+                    let report = fsck::check(&cloud, &fsck_options).await.unwrap();
+                    print!("{}", report);
+                },
+                "resume" => {
+                    // Продолжение прерванных загрузок
+                    for (path, result) in cloud.resume_uploads().await {
+                        match result {
+                            Ok(()) => println!("> {} загружен", path.display()),
+                            Err(e) => println!("! {} - {:?}", path.display(), e),
+                        }
+                    }
+                },
+                "q" => {
+                    let usage = "q u <файл> <папка VFS> [low|normal|high]\n\
+                                 q d <путь VFS> <папка или файл> [low|normal|high]\n\
+                                 q ls | q run | q clear | q pause|resume|retry <id> | q limit <id> <КБ/с|->";
+
+                    let Some(priority) = input_options.get(4).map_or(Some(Priority::default()), |priority| Priority::from_name(priority)) else {
+                        println!("{}", usage);
+                        continue;
+                    };
+
+                    match input_options[1..] {
+                        ["u", source, virtual_path, ..] => {
+                            let kind = JobKind::Upload {
+                                source: PathBuf::from(source),
+                                virtual_path: PathBuf::from(virtual_path),
+                            };
+                            println!("Задача #{} добавлена", cloud.enqueue(kind, priority).unwrap());
+                        },
+                        ["d", virtual_path, destination, ..] => {
+                            let kind = JobKind::Download {
+                                virtual_path: PathBuf::from(virtual_path),
+                                destination: PathBuf::from(destination),
+                                conflicts: ConflictPolicy::Overwrite,
+                            };
+                            println!("Задача #{} добавлена", cloud.enqueue(kind, priority).unwrap());
+                        },
+                        ["ls"] => match cloud.jobs() {
+                            Ok(jobs) => jobs.iter().for_each(|job| println!("{}", job)),
+                            Err(e) => println!("! {:?}", e),
+                        },
+                        ["run"] => print!("{}", cloud.run_queue().await.unwrap()),
+                        ["clear"] => cloud.clear_done_jobs().unwrap(),
+                        [action @ ("pause" | "resume" | "retry" | "limit"), id, ref rate_limit @ ..] => {
+                            let Ok(id) = id.parse() else {
+                                println!("{}", usage);
+                                continue;
+                            };
+
+                            let result = match (action, rate_limit.first()) {
+                                ("pause", _) => cloud.pause_job(id),
+                                ("resume", _) => cloud.resume_job(id),
+                                ("retry", _) => cloud.retry_job(id),
+                                (_, Some(rate_limit)) => {
+                                    let rate_limit = rate_limit.parse::<u64>().ok().map(|kbytes| kbytes * 1024);
+                                    cloud.set_job_rate_limit(id, rate_limit)
+                                },
+                                _ => {
+                                    println!("{}", usage);
+                                    continue;
+                                }
+                            };
+
+                            if let Err(e) = result {
+                                println!("! #{} - {:?}", id, e);
+                            }
+                        },
+                        _ => println!("{}", usage),
+                    }
+                },
+                "limit" => {
+                    // limit <загрузка КБ/с|-> <скачивание КБ/с|-> [ЧЧ:ММ-ЧЧ:ММ]
+                    // limit clear
+                    if input_options.get(1) == Some(&"clear") {
+                        cloud.set_bandwidth_schedule(BandwidthSchedule::default()).unwrap();
+                        println!("Ограничения скорости сняты");
+                        continue;
+                    }
+
+                    let usage = "limit <загрузка КБ/с|-> <скачивание КБ/с|-> [ЧЧ:ММ-ЧЧ:ММ] | limit clear";
+
+                    let [_, upload, download, ref period @ ..] = input_options[..] else {
+                        println!("{}", usage);
+                        continue;
+                    };
+
+                    let kbytes = |value: &str| value.parse::<u64>().ok().map(|kbytes| kbytes * 1024);
+                    let limit = RateLimit {
+                        upload: kbytes(upload),
+                        download: kbytes(download),
+                    };
+
+                    let mut schedule = cloud.bandwidth_schedule();
+
+                    match period.first() {
+                        Some(period) => {
+                            let time = |time: &str| chrono::NaiveTime::parse_from_str(time, "%H:%M").ok();
+
+                            let Some((Some(start), Some(end))) = period.split_once('-').map(|(start, end)| (time(start), time(end))) else {
+                                println!("{}", usage);
+                                continue;
+                            };
+
+                            schedule.rules.push(ScheduleRule { start, end, limit });
+                        },
+                        None => schedule.default = limit,
+                    }
+
+                    cloud.set_bandwidth_schedule(schedule).unwrap();
+                    println!("{:?}", cloud.bandwidth_schedule());
                 },
                 _ => println!("Unsupported command")
             }
@@ -1,20 +1,96 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::{fs, io, thread};
 use std::path::{Path, PathBuf};
+use std::future::Future;
 use std::time::Duration;
-use crate::virtual_file_system::{VirtualFileSystem, FSOption, FileSystemNode, VFSError, VFSFile, VFSFolder};
+use futures::{stream, StreamExt};
+use tokio::sync::{broadcast, Semaphore};
+use crate::virtual_file_system::{
+    VirtualFileSystem, FileSystemNode, VFSError, VFSFile, VFSFolder
+};
 use std::fs::File;
 use std::io::{ErrorKind, Read, Write};
 
 use telegram_drive_file::{Options as SeparationOptions, *};
-use telegram_drive_file::file_separation::{EncodeErrors, SeparationFile};
+use telegram_drive_file::file_separation::EncodeErrors;
+use telegram_drive_file::file_assembly::DecodeErrors;
+use telegram_drive_core::error::TDAppError;
 use crate::cloud_backend::{AsyncCloudBackend, CloudBackend};
+use crate::vfs_persistence::{self, VFSOperation};
+use crate::vfs_store::{VfsStore, VfsStoreKind};
+use crate::vfs_index::{self, VFSIndexError};
+use crate::vfs_merge::{self, MergeReport};
+use crate::folder_download::{self, ConflictPolicy};
+use crate::upload_session::UploadSession;
+use crate::download_session::DownloadSession;
+use crate::transfer_progress::{self, FileProgress, ProgressEvent, TransferPhase};
+use crate::rate_limit::{BandwidthSchedule, Throttle, TransferDirection};
+use crate::transfer_queue::{JobKind, JobStatus, Priority, QueueError, QueueReport, TransferJob, TransferQueue};
+
+pub use tokio_util::sync::CancellationToken;
 
 #[derive(Debug)]
 pub enum CloudError {
     IOError(std::io::Error),
     EncodeError(EncodeErrors),
+    DecodeError(DecodeErrors),
     VFSError(VFSError),
+    IndexError(VFSIndexError),
+    RemoteFileNotFound(String),
+    /// Некорректный glob шаблон
+    PatternError(globset::Error),
+    WatchError(notify::Error),
+    /// Не удалось передать части файла: имя части и ошибка
+    PartsFailed(Vec<(String, CloudError)>),
+    /// Скачанная часть не совпадает с контрольной суммой из VFS
+    ChecksumMismatch(String),
+    /// Передача отменена через `CancellationToken`
+    Cancelled,
+    QueueError(QueueError),
+    /// Ошибка TDLib, в том числе повторяемая ошибка после всех попыток
+    TelegramError(TDAppError),
+    /// Локальные файлы отличаются только расширением, а в VFS им соответствует один файл
+    NameCollision(Vec<PathBuf>),
+}
+
+impl From<TDAppError> for CloudError {
+    fn from(value: TDAppError) -> Self {
+        match value {
+            TDAppError::Cancelled => Self::Cancelled,
+            error => Self::TelegramError(error),
+        }
+    }
+}
+
+impl From<QueueError> for CloudError {
+    fn from(value: QueueError) -> Self {
+        Self::QueueError(value)
+    }
+}
+
+impl From<notify::Error> for CloudError {
+    fn from(value: notify::Error) -> Self {
+        Self::WatchError(value)
+    }
+}
+
+impl From<globset::Error> for CloudError {
+    fn from(value: globset::Error) -> Self {
+        Self::PatternError(value)
+    }
+}
+
+impl From<VFSIndexError> for CloudError {
+    fn from(value: VFSIndexError) -> Self {
+        Self::IndexError(value)
+    }
+}
+
+impl From<io::Error> for CloudError {
+    fn from(value: io::Error) -> Self {
+        Self::IOError(value)
+    }
 }
 
 impl From<VFSError> for CloudError {
@@ -24,63 +100,463 @@ impl From<VFSError> for CloudError {
 }
 impl From<EncodeErrors> for CloudError {
     fn from(value: EncodeErrors) -> Self {
-        Self::EncodeError(value)
+        match value {
+            EncodeErrors::Cancelled => Self::Cancelled,
+            value => Self::EncodeError(value),
+        }
+    }
+}
+
+impl From<DecodeErrors> for CloudError {
+    fn from(value: DecodeErrors) -> Self {
+        match value {
+            DecodeErrors::Cancelled => Self::Cancelled,
+            value => Self::DecodeError(value),
+        }
+    }
+}
+
+/// Частей одного файла, передаваемых одновременно, по умолчанию
+const DEFAULT_PARTS_PER_FILE: usize = 4;
+/// Частей всех файлов, передаваемых одновременно, по умолчанию
+const DEFAULT_TRANSFER_SLOTS: usize = 8;
+/// Размер части по умолчанию и наибольшее кол-во частей файла при разбиении
+const DEFAULT_PART_SIZE: u64 = 1 << 30;
+const MAX_PARTS_PER_FILE: u64 = 255;
+/// При ограничении скорости загрузки часть передается примерно за столько секунд
+const PACED_PART_SECS: u64 = 1;
+const MIN_PACED_PART_SIZE: u64 = 64 * 1024;
+/// Как часто выполняемая задача проверяет, не приостановил ли ее другой процесс
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Параметры одной передачи файла
+#[derive(Debug, Clone, Default)]
+pub struct TransferContext {
+    /// Отмена передачи: переданные части и сессия передачи удаляются
+    pub cancel: CancellationToken,
+    /// Ограничение скорости этой передачи в байтах в секунду, действует вместе с общим
+    pub rate_limit: Option<u64>,
+    /// Передача остановлена через `pause`, а не отменена
+    pub(crate) paused: CancellationToken,
+}
+
+impl TransferContext {
+    /// Остановка передачи с сохранением переданных частей и сессии, чтобы ее можно было продолжить
+    pub fn pause(&self) {
+        self.paused.cancel();
+        self.cancel.cancel();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_cancelled()
     }
 }
 
 #[derive(Debug, Clone)]
 struct CloudOptions {
-    work_dir: PathBuf
+    work_dir: PathBuf,
+    /// Папка, в которой лежит хранилище VFS
+    data_dir: PathBuf,
+    /// Пароль для шифрования снимков VFS в облаке. Без пароля снимки не выгружаются
+    index_passphrase: Option<String>,
+    /// Кол-во мутаций VFS, после которого снимок выгружается в облако
+    index_sync_interval: usize,
+    /// Кол-во хранимых в облаке версий снимка VFS
+    index_keep_versions: usize,
+    /// Идентификатор устройства, которым подписываются ревизии VFS
+    device_id: String,
+    /// Сколько частей одного файла передается одновременно
+    parts_per_file: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Cloud<T: AsyncCloudBackend> {
     fs: RefCell<VirtualFileSystem>,
+    store: RefCell<Box<dyn VfsStore>>,
+    /// Кол-во мутаций VFS с момента последней выгрузки снимка
+    unsynced_operations: Cell<usize>,
     backend: T,
     option: CloudOptions,
+    /// Общее ограничение одновременно передаваемых частей для всех файлов
+    transfer_slots: Semaphore,
+    progress: broadcast::Sender<ProgressEvent>,
+    /// Общее ограничение скорости по времени суток
+    bandwidth: RefCell<BandwidthSchedule>,
+    upload_throttle: Throttle,
+    download_throttle: Throttle,
+    /// Параметры выполняемых этим процессом задач очереди по id задачи
+    running_jobs: RefCell<HashMap<u64, TransferContext>>,
 }
 
 impl<T: AsyncCloudBackend> Cloud<T> {
 
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self, CloudError> {
+        Cloud::with_backend(
+            T::create(),
+            Path::new("."),
+            Path::new("./td/file/documents/")
+        )
+    }
+
+    /// Создание облака с готовым бэкендом.
+    /// `data_dir` - папка хранилища VFS, `work_dir` - папка, куда бэкенд скачивает файлы.
+    /// Если хранилище VFS не удалось открыть или прочитать, облако не создается, чтобы
+    /// пустая VFS не заменила сохраненную
+    pub fn with_backend(backend: T, data_dir: &Path, work_dir: &Path) -> Result<Self, CloudError> {
 
-        let try_open_vfs = File::open("vfs.json");
+        let option = CloudOptions {
+            work_dir: work_dir.to_path_buf(),
+            data_dir: data_dir.to_path_buf(),
+            index_passphrase: std::env::var("TELEGRAM_DRIVE_INDEX_KEY").ok(),
+            index_sync_interval: 20,
+            index_keep_versions: 5,
+            device_id: load_device_id(data_dir)?,
+            parts_per_file: DEFAULT_PARTS_PER_FILE,
+        };
 
-        let vfs_from_backup =
-            match try_open_vfs {
-                Ok(mut f) => serde_json::from_reader::<File, VirtualFileSystem>(f).unwrap(),
+        let store_kind = VfsStoreKind::detect(&option.data_dir);
+        let mut store = store_kind.open(&option.data_dir)?;
+        let vfs = store.load()?;
+
+        Ok(Cloud {
+            fs: RefCell::new(vfs),
+            store: RefCell::new(store),
+            unsynced_operations: Cell::new(0),
+            backend,
+            option,
+            transfer_slots: Semaphore::new(DEFAULT_TRANSFER_SLOTS),
+            progress: broadcast::channel(transfer_progress::PROGRESS_CHANNEL_CAPACITY).0,
+            bandwidth: RefCell::new(BandwidthSchedule::load(data_dir).unwrap_or_else(|e| {
+                println!("Не удалось прочитать расписание скорости, скорость не ограничена: {}", e);
+                BandwidthSchedule::default()
+            })),
+            upload_throttle: Throttle::new(),
+            download_throttle: Throttle::new(),
+            running_jobs: RefCell::new(HashMap::new()),
+        })
+    }
 
-                Err(e) => match e.kind() {
-                    ErrorKind::NotFound => VirtualFileSystem::new(FSOption::default()),
-                    _ => panic!("{}", e)
+    /// Загрузка VFS из облака на новом устройстве, где локальная VFS пуста.
+    /// Вызывается после создания облака
+    pub async fn pull_initial_index(&self) {
+        if !self.fs.borrow().nodes().is_empty() || self.option.index_passphrase.is_none() {
+            return;
+        }
+
+        match self.pull_index().await {
+            Ok(Some((version, _))) => println!("VFS загружена из облака, версия {}", version),
+            Ok(None) => {},
+            Err(e) => println!("Не удалось загрузить VFS из облака: {:?}", e),
+        }
+    }
+
+    /// Подписка на события прогресса разбиения, загрузки, скачивания и сборки файлов
+    pub fn subscribe_progress(&self) -> broadcast::Receiver<ProgressEvent> {
+        self.progress.subscribe()
+    }
+
+    /// Ограничения параллельной передачи: частей одного файла и частей всех файлов вместе
+    pub fn set_transfer_limits(&mut self, parts_per_file: usize, total_parts: usize) {
+        self.option.parts_per_file = parts_per_file.max(1);
+        self.transfer_slots = Semaphore::new(total_parts.max(1));
+    }
+
+    /// Расписание общего ограничения скорости, сохраняется в папке данных
+    pub fn set_bandwidth_schedule(&self, schedule: BandwidthSchedule) -> Result<(), CloudError> {
+        schedule.save(&self.option.data_dir)?;
+        *self.bandwidth.borrow_mut() = schedule;
+        Ok(())
+    }
+
+    pub fn bandwidth_schedule(&self) -> BandwidthSchedule {
+        self.bandwidth.borrow().clone()
+    }
+
+    /// Ожидание, пока общее ограничение скорости и ограничение передачи позволят передать `bytes` байт
+    async fn throttle(&self, direction: TransferDirection, transfer_throttle: &Throttle, rate_limit: Option<u64>, bytes: u64) {
+        let global_limit = self.bandwidth
+            .borrow()
+            .limit_at(chrono::Local::now().time())
+            .for_direction(direction);
+
+        let global_throttle = match direction {
+            TransferDirection::Upload => &self.upload_throttle,
+            TransferDirection::Download => &self.download_throttle,
+        };
+
+        tokio::join!(
+            global_throttle.acquire(bytes, global_limit),
+            transfer_throttle.acquire(bytes, rate_limit)
+        );
+    }
+
+    /// Параллельная передача частей из рабочей папки.
+    ///
+    /// Одновременно передается не больше `parts_per_file` частей файла и не больше
+    /// `transfer_slots` частей всех файлов. `on_done` вызывается для каждой переданной части
+    /// по мере завершения. Ошибка одной части не прерывает остальные, ошибки всех частей
+    /// возвращаются вместе. После отмены передачи новые части не начинаются.
+    ///
+    /// Части передаются с учетом ограничений скорости: часть начинается, когда ограничения
+    /// позволяют передать ее размер. Часть неизвестного размера (0) учитывается после передачи.
+    /// Сама часть передается на полной скорости, поэтому при ограничении загрузки файл
+    /// разбивается на небольшие части (см. `paced_part_size`).
+    async fn transfer_parts<'a, R, F, Fut>(
+        &'a self,
+        parts: &'a [(String, u64)],
+        direction: TransferDirection,
+        context: &'a TransferContext,
+        transfer: F,
+        mut on_done: impl FnMut(&str, R) -> Result<(), CloudError>
+    ) -> Result<(), CloudError>
+    where
+        F: Fn(PathBuf) -> Fut,
+        Fut: Future<Output = Result<R, CloudError>> + 'a,
+    {
+        let transfer_throttle = &Throttle::new();
+
+        let mut transfers = stream::iter(parts)
+            .map(|(part_name, size)| {
+                let part_path = self.option.work_dir.join(part_name);
+                let transfer_part = transfer(part_path.clone());
+
+                async move {
+                    // Отмена проверяется первой, в том числе пока часть ждет слот или ограничение скорости
+                    let _slot = tokio::select! {
+                        biased;
+                        _ = context.cancel.cancelled() => return (part_name, Err(CloudError::Cancelled)),
+                        slot = async {
+                            let slot = self.transfer_slots.acquire().await.unwrap();
+                            self.throttle(direction, transfer_throttle, context.rate_limit, *size).await;
+                            slot
+                        } => slot,
+                    };
+
+                    if context.cancel.is_cancelled() {
+                        return (part_name, Err(CloudError::Cancelled));
+                    }
+
+                    let result = transfer_part.await;
+
+                    if *size == 0 && result.is_ok() {
+                        let size = fs::metadata(&part_path).map(|metadata| metadata.len()).unwrap_or_default();
+                        self.throttle(direction, transfer_throttle, context.rate_limit, size).await;
+                    }
+
+                    (part_name, result)
                 }
-            };
+            })
+            .buffer_unordered(self.option.parts_per_file);
 
-        Cloud {
-            fs: RefCell::new(vfs_from_backup),
-            backend: T::create(),
-            option: CloudOptions {
-                work_dir: PathBuf::from("./td/file/documents/")
-            },
+        let mut failed = vec![];
+
+        while let Some((part_name, result)) = transfers.next().await {
+            if let Err(e) = result.and_then(|transferred| on_done(part_name, transferred)) {
+                failed.push((part_name.clone(), e));
+            }
+        }
+
+        if failed.is_empty() {
+            return Ok(());
+        }
+
+        failed.sort_by(|a, b| a.0.cmp(&b.0));
+        Err(CloudError::PartsFailed(failed))
+    }
+
+    /// Применение мутации к VFS с сохранением в хранилище
+    fn apply_operation(&self, mut operation: VFSOperation) -> Result<(), CloudError> {
+
+        let mut fs = self.fs.borrow_mut();
+
+        operation.stamp(&fs, &self.option.device_id);
+
+        // Операция применяется на месте, при ошибке откатываются только затронутые узлы
+        let rollback = operation.rollback(&fs);
+
+        let result = operation
+            .apply(&mut fs)
+            .map_err(CloudError::from)
+            .and_then(|()| self.store.borrow_mut().apply(&operation, &fs).map_err(CloudError::from));
+
+        if let Err(e) = result {
+            rollback.restore(&mut fs);
+            return Err(e);
+        }
+
+        self.unsynced_operations.set(self.unsynced_operations.get() + 1);
+
+        Ok(())
+    }
+
+    /// Выгрузка зашифрованного снимка VFS в облако новой версией.
+    /// Перед выгрузкой в VFS вливаются более новые снимки других устройств
+    pub async fn push_index(&self) -> Result<u64, CloudError> {
+
+        let passphrase = self.option.index_passphrase
+            .as_ref()
+            .ok_or(CloudError::IndexError(VFSIndexError::NoPassphrase))?;
+
+        if let Some((_, report)) = self.pull_index().await? {
+            if !report.is_empty() {
+                print!("{}", report);
+            }
+        }
+
+        let remote_versions = self.remote_index_versions().await?;
+
+        let version = remote_versions
+            .iter()
+            .map(|(version, _)| *version)
+            .max()
+            .unwrap_or(0)
+            .max(self.fs.borrow().options.version() as u64) + 1;
+
+        self.fs.borrow_mut().options.set_version(version as i64);
+        self.fs.borrow_mut().options.set_pulled_version(&self.option.device_id, version);
+        self.fs.borrow_mut().record_seen(&self.option.device_id);
+
+        let index_name = vfs_index::index_file_name(version, &self.option.device_id);
+        let index_path = self.option.work_dir.join(&index_name);
+
+        fs::write(&index_path, vfs_index::encrypt_index(&self.fs.borrow(), passphrase)?)?;
+
+        self.backend.upload_file(&index_path).await?;
+        self.backend.pin_file(&index_name).await?;
+        self.save_vfs()?;
+
+        // Старые версии снимка удаляются
+        let oldest_kept_version = (version + 1).saturating_sub(self.option.index_keep_versions as u64);
+
+        for (old_version, old_index_name) in remote_versions {
+            if old_version < oldest_kept_version {
+                self.backend.remove_file(Path::new(&old_index_name)).await?;
+            }
+        }
+
+        self.unsynced_operations.set(0);
+
+        Ok(version)
+    }
+
+    /// Слияние локальной VFS с последними снимками устройств, которые еще не вливались.
+    /// Слитая VFS записывается в хранилище целиком, и только после этого заменяет VFS в памяти.
+    /// Возвращает последнюю влитую версию и отчет о слиянии
+    pub async fn pull_index(&self) -> Result<Option<(u64, MergeReport)>, CloudError> {
+
+        let passphrase = self.option.index_passphrase
+            .as_ref()
+            .ok_or(CloudError::IndexError(VFSIndexError::NoPassphrase))?;
+
+        let index_names = self.remote_index_versions()
+            .await?
+            .into_iter()
+            .map(|(_, index_name)| index_name)
+            .collect::<Vec<_>>();
+
+        let new_snapshots = vfs_index::snapshots_to_pull(&index_names, self.fs.borrow().options.pulled_versions());
+
+        if new_snapshots.is_empty() {
+            return Ok(None);
+        }
+
+        let mut merged = self.fs.borrow().clone();
+        let mut full_report = MergeReport::default();
+        let mut last_version = merged.options.version() as u64;
+
+        for (version, device_id, index_name) in new_snapshots {
+            let remote_vfs = self.download_index(&index_name, passphrase).await?;
+
+            let (next, report) = vfs_merge::merge(&merged, &remote_vfs, &self.option.device_id);
+            merged = next;
+            merged.options.set_pulled_version(&device_id, version);
+
+            full_report.applied.extend(report.applied);
+            full_report.removed.extend(report.removed);
+            full_report.conflicts.extend(report.conflicts);
+            last_version = last_version.max(version);
+        }
+
+        merged.options.set_version(last_version as i64);
+
+        // Снимок записывается как контрольная точка хранилища: журнал операций до слияния
+        // очищается только вместе с записью слитой VFS
+        self.store.borrow_mut().save(&merged)?;
+        *self.fs.borrow_mut() = merged;
+
+        Ok(Some((last_version, full_report)))
+    }
+
+    /// Выгрузка снимка VFS, если накопилось достаточно изменений
+    pub async fn sync_index_if_needed(&self) -> Result<(), CloudError> {
+        if self.option.index_passphrase.is_none()
+            || self.unsynced_operations.get() < self.option.index_sync_interval
+        {
+            return Ok(());
         }
+
+        self.push_index().await.map(|_| ())
     }
 
-    fn save_vfs(&self) -> io::Result<()> {
-        // let result_f = dbg!(File::open("vfs.json"));
-        // match result_f {
-        //     Ok(mut f) => {
-        //         f.write_all(&vec![]).unwrap();
-        //     }
-        //     Err(e) => match e.kind() {
-        //         ErrorKind::NotFound => {
-        //             let vfs_json = serde_json::to_string(&*self.fs.borrow()).unwrap();
-        //             fs::write("vfs.json", vfs_json.as_bytes())?;
-        //         }
-        //         _ => return Err(e)
-        //     }
-        // }
-        let vfs_json = serde_json::to_string(&*self.fs.borrow()).unwrap();
-        fs::write("vfs.json", vfs_json.as_bytes())?;
+    /// Все снимки VFS, хранящиеся в облаке
+    pub async fn remote_index_snapshots(&self) -> Result<Vec<VirtualFileSystem>, CloudError> {
+
+        let passphrase = self.option.index_passphrase
+            .as_ref()
+            .ok_or(CloudError::IndexError(VFSIndexError::NoPassphrase))?;
+
+        let mut snapshots = vec![];
+
+        for (_, index_name) in self.remote_index_versions().await? {
+            snapshots.push(self.download_index(&index_name, passphrase).await?);
+        }
+
+        Ok(snapshots)
+    }
+
+    pub fn has_index_passphrase(&self) -> bool {
+        self.option.index_passphrase.is_some()
+    }
+
+    async fn download_index(&self, index_name: &str, passphrase: &str) -> Result<VirtualFileSystem, CloudError> {
+        self.backend.download_file(Path::new(index_name)).await?;
+
+        let index_data = fs::read(self.option.work_dir.join(index_name))?;
+
+        Ok(vfs_index::decrypt_index(&index_data, passphrase)?)
+    }
+
+    /// Снимки VFS в облаке: версия и имя файла
+    async fn remote_index_versions(&self) -> Result<Vec<(u64, String)>, CloudError> {
+        Ok(self.backend
+            .list_files()
+            .await?
+            .into_iter()
+            .filter_map(|file| vfs_index::parse_index_version(&file.name).map(|version| (version, file.name)))
+            .collect())
+    }
+
+    /// Принудительная запись всей VFS в хранилище
+    pub fn save_vfs(&self) -> Result<(), CloudError> {
+        self.store
+            .borrow_mut()
+            .save(&self.fs.borrow())
+            .map_err(|e| e.into())
+    }
+
+    /// Перенос VFS в хранилище другого типа. Новое хранилище становится текущим
+    pub fn migrate_store(&self, kind: VfsStoreKind) -> Result<(), CloudError> {
+        let old_kind = VfsStoreKind::detect(&self.option.data_dir);
+        if old_kind == kind {
+            return Ok(());
+        }
+
+        let mut new_store = kind.open(&self.option.data_dir)?;
+        new_store.save(&self.fs.borrow())?;
+
+        // Старое хранилище закрывается до переименования
+        drop(self.store.replace(new_store));
+        old_kind.retire(&self.option.data_dir)?;
 
         Ok(())
     }
@@ -89,11 +565,15 @@ impl<T: AsyncCloudBackend> Cloud<T> {
         serde_json::to_string(&*self.fs.borrow()).unwrap()
     }
 
+    pub fn get_vfs(&self) -> VirtualFileSystem {
+        self.fs.borrow().clone()
+    }
+
     pub fn get_file(&self, path: &Path) -> Result<VFSFile, CloudError> {
         self.fs
             .borrow()
             .get_file(path)
-            .map(|file| file.clone())
+            .cloned()
             .map_err(|err|err.into())
     }
 
@@ -101,121 +581,667 @@ impl<T: AsyncCloudBackend> Cloud<T> {
         self.fs
             .borrow()
             .get_folder(path)
-            .map(|folder| folder.clone())
+            .cloned()
             .map_err(|err|err.into())
     }
 
+    pub fn backend(&self) -> &T {
+        &self.backend
+    }
+
+    /// Папка, в которую сохраняются части файлов
+    pub fn work_dir(&self) -> &Path {
+        &self.option.work_dir
+    }
+
+    /// Добавление в VFS уже загруженного в облако файла
+    pub fn add_file(&self, virtual_path: &Path, file: VFSFile) -> Result<(), CloudError> {
+        self.apply_operation(VFSOperation::AddFile {
+            path: virtual_path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Создание папки вместе со всеми отсутствующими родительскими папками
+    pub fn create_folder(&self, virtual_path: &Path) -> Result<(), CloudError> {
+
+        let mut current_path = PathBuf::new();
+
+        for path_part in virtual_path.iter() {
+            let is_root = current_path.as_os_str().is_empty();
+            current_path.push(path_part);
+
+            if is_root || self.fs.borrow().get_folder(&current_path).is_ok() {
+                continue;
+            }
+
+            self.apply_operation(VFSOperation::AddFolder {
+                path: current_path.parent().unwrap().to_path_buf(),
+                folder: VFSFolder {
+                    name: path_part.to_string_lossy().to_string(),
+                    metadata: Default::default(),
+                    children: Default::default(),
+                },
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Загрузка файла в папку VFS `virtual_path`.
+    ///
+    /// Загрузка идет в рамках сессии, сохраняемой на диск после каждой части. Если загрузка
+    /// этого же файла была прервана, она продолжается с первой незагруженной части.
+    /// Файл попадает в VFS только после загрузки всех частей и метафайла.
     pub async fn async_upload_file(
         &self,
         file_path: &PathBuf,
         virtual_path: &Path
     ) -> Result<(), CloudError> {
+        self.async_upload_file_with_context(file_path, virtual_path, &TransferContext::default()).await
+    }
+
+    /// Загрузка файла с отменой и ограничением скорости из `context`.
+    ///
+    /// При отмене останавливается разбиение файла и передача частей, а уже загруженные
+    /// части, локальные части и сессия загрузки удаляются. Файл в VFS не попадает.
+    pub async fn async_upload_file_with_context(
+        &self,
+        file_path: &PathBuf,
+        virtual_path: &Path,
+        context: &TransferContext
+    ) -> Result<(), CloudError> {
+        self.upload_file(file_path, virtual_path, context, false).await
+    }
+
+    /// Загрузка новой версии файла. Файл с тем же именем в VFS заменяется только
+    /// после загрузки новой версии, при ошибке загрузки он остается на месте
+    pub async fn async_replace_file(
+        &self,
+        file_path: &PathBuf,
+        virtual_path: &Path
+    ) -> Result<(), CloudError> {
+        self.upload_file(file_path, virtual_path, &TransferContext::default(), true).await
+    }
+
+    async fn upload_file(
+        &self,
+        file_path: &PathBuf,
+        virtual_path: &Path,
+        context: &TransferContext,
+        replace: bool
+    ) -> Result<(), CloudError> {
+        let cancel = &context.cancel;
+
+        let session = self.resumable_session(file_path, virtual_path).await?;
+        let replace = replace || session.as_ref().is_some_and(|session| session.replace);
+
+        // Файл, который не получится добавить в VFS, не загружается
+        if let Err(e) = self.check_upload_target(file_path, virtual_path, replace) {
+            if let Some(session) = &session {
+                self.abort_upload(session).await;
+            }
+            return Err(e);
+        }
+
+        let mut session = match session {
+            Some(session) => {
+                println!(
+                    "Продолжение загрузки {}: загружено частей {} из {}",
+                    file_path.display(),
+                    session.uploaded_parts(),
+                    session.parts.len()
+                );
+                session
+            },
+            None => self.start_upload_session(file_path, virtual_path, context)?,
+        };
+        session.replace = replace;
+
+        if let Err(e) = self.upload_session_files(&mut session, file_path, context).await {
+            if cancel.is_cancelled() {
+                // Приостановленная загрузка продолжается с сохраненной сессии
+                if !context.is_paused() {
+                    self.abort_upload(&session).await;
+                }
+                return Err(CloudError::Cancelled);
+            }
+            return Err(e);
+        }
+
+        // Без файла в VFS на загруженные части никто не ссылается
+        if let Err(e) = self.add_file_to_vfs(&session) {
+            self.abort_upload(&session).await;
+            return Err(e);
+        }
+        session.remove(&self.option.data_dir)?;
+
+        self.sync_index_if_needed().await?;
+
+        Ok(())
+    }
+
+    /// Проверка до загрузки, что папка `virtual_path` существует, а имя файла в ней свободно
+    /// или занято файлом, который заменяется
+    fn check_upload_target(&self, file_path: &Path, virtual_path: &Path, replace: bool) -> Result<(), CloudError> {
+        let name = file_path.file_stem().unwrap_or_default().to_string_lossy();
+        let fs = self.fs.borrow();
+
+        match fs.get_folder(virtual_path)?.children.get(name.as_ref()) {
+            None => Ok(()),
+            Some(FileSystemNode::File(_)) if replace => Ok(()),
+            Some(FileSystemNode::File(_)) => Err(VFSError::FileAlreadyExists.into()),
+            Some(FileSystemNode::Folder(_)) => Err(VFSError::FolderAlreadyExists.into()),
+        }
+    }
+
+    /// Загрузка незагруженных частей сессии, затем метафайла
+    async fn upload_session_files(
+        &self,
+        session: &mut UploadSession,
+        file_path: &Path,
+        context: &TransferContext
+    ) -> Result<(), CloudError> {
+
+        let missing_parts = session.missing_parts();
+
+        let progress = FileProgress::new(&self.progress, file_path, TransferPhase::Upload, session
+            .files()
+            .map(|part| {
+                let done = if part.remote_id.is_some() { part.checksum.size } else { 0 };
+                (part.name.clone(), done, part.checksum.size)
+            })
+        );
+        let progress = &progress;
+
+        self.transfer_parts(&missing_parts, TransferDirection::Upload, context, |part_path| async move {
+            let part_name = part_path.file_name().unwrap().to_string_lossy().to_string();
+
+            self.backend
+                .upload_file_with_progress(&part_path, &|done, total| progress.update(&part_name, done, total), &context.cancel)
+                .await
+        }, |part_name, remote_file| {
+            progress.finish(part_name);
+            session.confirm(part_name, remote_file.id);
+            session.save(&self.option.data_dir).map_err(|e| e.into())
+        }).await?;
+
+        if session.metafile.remote_id.is_none() {
+            let remote_file = self.backend
+                .upload_file_with_progress(&self.option.work_dir.join(&session.metafile.name), &|_, _| {}, &context.cancel)
+                .await?;
+
+            let metafile_name = session.metafile.name.clone();
+            progress.finish(&metafile_name);
+            session.confirm(&metafile_name, remote_file.id);
+            session.save(&self.option.data_dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// Удаление всего, что оставила отмененная загрузка: частей в облаке,
+    /// частей в рабочей папке и файла сессии
+    async fn abort_upload(&self, session: &UploadSession) {
+        for part in session.files() {
+            if part.remote_id.is_some() {
+                if let Err(e) = self.backend.remove_file(Path::new(&part.name)).await {
+                    println!("Не удалось удалить {} из облака: {:?}", part.name, e);
+                }
+            }
+
+            let _ = fs::remove_file(self.option.work_dir.join(&part.name));
+        }
+
+        let _ = session.remove(&self.option.data_dir);
+    }
+
+    /// Незавершенные загрузки
+    pub fn upload_sessions(&self) -> Vec<UploadSession> {
+        UploadSession::load_all(&self.option.data_dir)
+    }
+
+    /// Продолжение всех незавершенных загрузок
+    pub async fn resume_uploads(&self) -> Vec<(PathBuf, Result<(), CloudError>)> {
+        let mut results = vec![];
+
+        for session in self.upload_sessions() {
+            let result = self.async_upload_file(&session.source_path, &session.virtual_path).await;
+            results.push((session.source_path, result));
+        }
+
+        results
+    }
+
+    /// Добавление передачи в очередь, возвращает id задачи
+    pub fn enqueue(&self, kind: JobKind, priority: Priority) -> Result<u64, CloudError> {
+        self.update_queue(|queue| Ok(queue.push(kind, priority)))
+    }
+
+    /// Задачи очереди, в том числе добавленные другими процессами
+    pub fn jobs(&self) -> Result<Vec<TransferJob>, CloudError> {
+        Ok(TransferQueue::load(&self.option.data_dir)?.jobs().to_vec())
+    }
+
+    /// Приостановка задачи. Выполняемая задача останавливается, а переданные части
+    /// и сессия передачи сохраняются, поэтому после возобновления она продолжается
+    pub fn pause_job(&self, id: u64) -> Result<(), CloudError> {
+        self.update_queue(|queue| queue.pause(id))?;
+
+        if let Some(context) = self.running_jobs.borrow().get(&id) {
+            context.pause();
+        }
+
+        Ok(())
+    }
+
+    pub fn resume_job(&self, id: u64) -> Result<(), CloudError> {
+        self.update_queue(|queue| queue.resume(id))
+    }
+
+    /// Повторная постановка в очередь задачи, завершившейся ошибкой
+    pub fn retry_job(&self, id: u64) -> Result<(), CloudError> {
+        self.update_queue(|queue| queue.retry(id))
+    }
+
+    /// Ограничение скорости задачи в байтах в секунду, `None` - только общее ограничение
+    pub fn set_job_rate_limit(&self, id: u64, rate_limit: Option<u64>) -> Result<(), CloudError> {
+        self.update_queue(|queue| queue.set_rate_limit(id, rate_limit))
+    }
+
+    pub fn clear_done_jobs(&self) -> Result<(), CloudError> {
+        self.update_queue(|queue| {
+            queue.clear_done();
+            Ok(())
+        })
+    }
+
+    /// Выполнение задач очереди по приоритету, пока в очереди есть задачи.
+    ///
+    /// Задачи выполняются по одной, части файла при этом передаются параллельно.
+    /// Задачи, добавленные или возобновленные во время обработки, в том числе другими
+    /// процессами, тоже выполняются. Задача, приостановленная во время выполнения,
+    /// останавливается и остается в очереди приостановленной вместе с сессией передачи.
+    pub async fn run_queue(&self) -> Result<QueueReport, CloudError> {
+        let mut report = QueueReport::default();
+
+        loop {
+            let Some((job, _job_lock)) = TransferQueue::start_next(&self.option.data_dir)? else {
+                break;
+            };
+
+            println!("Задача #{} запущена", job.id);
+
+            let context = TransferContext { rate_limit: job.rate_limit, ..TransferContext::default() };
+            self.running_jobs.borrow_mut().insert(job.id, context.clone());
+
+            let transfer = async {
+                match &job.kind {
+                    JobKind::Upload { source, virtual_path } =>
+                        self.upload_file(source, virtual_path, &context, false).await,
+                    JobKind::Download { virtual_path, destination, conflicts } =>
+                        self.async_download_file_with_context(virtual_path, destination, *conflicts, &context)
+                            .await
+                            .map(|_| ()),
+                }
+            };
+
+            let result = tokio::select! {
+                result = transfer => result,
+                _ = self.watch_job(job.id, &context) => unreachable!(),
+            };
+
+            self.running_jobs.borrow_mut().remove(&job.id);
+
+            let status = match result {
+                Ok(()) => {
+                    report.done.push(job.id);
+                    JobStatus::Done
+                },
+                Err(CloudError::Cancelled) => {
+                    println!("Задача #{} приостановлена", job.id);
+                    JobStatus::Paused
+                },
+                Err(e) => {
+                    let error = format!("{:?}", e);
+                    report.failed.push((job.id, error.clone()));
+                    JobStatus::Failed(error)
+                },
+            };
+
+            self.update_queue(|queue| queue.set_status(
+                job.id,
+                |status| matches!(status, JobStatus::Running | JobStatus::Paused),
+                status
+            ))?;
+        }
+
+        Ok(report)
+    }
+
+    /// Остановка задачи, если ее приостановил другой процесс. Не завершается
+    async fn watch_job(&self, id: u64, context: &TransferContext) {
+        loop {
+            tokio::time::sleep(QUEUE_POLL_INTERVAL).await;
+
+            let paused = TransferQueue::load(&self.option.data_dir)
+                .map(|queue| queue.jobs().iter().any(|job| job.id == id && job.status == JobStatus::Paused))
+                .unwrap_or(false);
+
+            if paused {
+                context.pause();
+                return std::future::pending().await;
+            }
+        }
+    }
+
+    /// Изменение очереди под блокировкой: очередь перечитывается из папки данных,
+    /// изменяется и сохраняется обратно
+    fn update_queue<R>(&self, update: impl FnOnce(&mut TransferQueue) -> Result<R, QueueError>) -> Result<R, CloudError> {
+        Ok(TransferQueue::update(&self.option.data_dir, update)??)
+    }
+
+    /// Разбиение файла на части и создание новой сессии загрузки
+    fn start_upload_session(
+        &self,
+        file_path: &PathBuf,
+        virtual_path: &Path,
+        context: &TransferContext
+    ) -> Result<UploadSession, CloudError> {
         use telegram_drive_file::file_separation;
 
+        let cancel = &context.cancel;
+
         let options = SeparationOptions {
             path_for_save: Some(self.option.work_dir.clone()),
             count_parts: None,
-            part_size: None,
+            part_size: self.paced_part_size(fs::metadata(file_path)?.len(), context.rate_limit),
             compressed: None,
         };
 
-        let separation_file =
-            dbg!(file_separation::encode_file(dbg!(file_path), options)?);
+        let separation_file = file_separation::encode_file_with_progress(file_path, options, |done, total| {
+            let _ = self.progress.send(transfer_progress::file_event(file_path, TransferPhase::Split, done, total));
+        }, || cancel.is_cancelled())?;
+
+        let session = UploadSession::new(file_path, virtual_path, &separation_file, &self.option.work_dir)?;
+        session.save(&self.option.data_dir)?;
+
+        Ok(session)
+    }
+
+    /// Размер части при ограничении скорости загрузки, `None` - без ограничения.
+    ///
+    /// Ограничение учитывается только перед началом части, поэтому часть должна передаваться
+    /// за `PACED_PART_SECS` при действующем ограничении, а не быть гигабайтной по умолчанию.
+    /// Кол-во частей при этом не превышает `MAX_PARTS_PER_FILE`
+    fn paced_part_size(&self, file_size: u64, rate_limit: Option<u64>) -> Option<usize> {
+        let global_limit = self.bandwidth
+            .borrow()
+            .limit_at(chrono::Local::now().time())
+            .for_direction(TransferDirection::Upload);
 
-        self.add_file_to_vfs(&separation_file, virtual_path)?;
+        let limit = [global_limit, rate_limit]
+            .into_iter()
+            .flatten()
+            .filter(|limit| *limit > 0)
+            .min()?;
 
-        for part_file in &separation_file.parts {
+        let part_size = limit
+            .saturating_mul(PACED_PART_SECS)
+            .max(MIN_PACED_PART_SIZE)
+            .max(file_size.div_ceil(MAX_PARTS_PER_FILE))
+            .min(DEFAULT_PART_SIZE);
 
-            let mut part_path = self.option.work_dir.clone();
-            part_path.push(&part_file.part_file_name);
+        Some(part_size as usize)
+    }
+
+    /// Сохраненная сессия загрузки файла, которую можно продолжить.
+    ///
+    /// Сессия отбрасывается, если файл изменился или незагруженных частей уже нет в рабочей
+    /// папке. Части, пропавшие из облака, загружаются заново.
+    async fn resumable_session(&self, file_path: &Path, virtual_path: &Path) -> Result<Option<UploadSession>, CloudError> {
+        let Some(mut session) = UploadSession::find(&self.option.data_dir, file_path, virtual_path) else {
+            return Ok(None);
+        };
 
-            self.backend.upload_file(&part_path).await?;
+        for part in session.files_mut() {
+            if part.remote_id.is_some() && !self.backend.check_file(&part.name).await {
+                part.remote_id = None;
+            }
         }
 
-        let mut metafile_path = self.option.work_dir.clone();
-        metafile_path.push(&separation_file.metafile);
+        let is_resumable = session.is_source_unchanged() && session
+            .files()
+            .filter(|part| part.remote_id.is_none())
+            .all(|part| self.option.work_dir.join(&part.name).is_file());
 
-        self.backend.upload_file(&metafile_path).await?;
+        if !is_resumable {
+            println!("Прерванная загрузка {} начата заново", file_path.display());
+            session.remove(&self.option.data_dir)?;
+            return Ok(None);
+        }
 
-        self.save_vfs().unwrap();
-        return Ok(());
+        Ok(Some(session))
     }
 
-    pub async fn async_download_file(&self, virtual_path: &Path) -> Result<PathBuf, CloudError> {
+    /// Скачивание файла VFS в `destination`.
+    ///
+    /// `destination` - папка, в которую файл сохраняется под своим именем, или путь до файла.
+    /// Если файл уже существует, он обрабатывается согласно `conflicts`; `None` - файл
+    /// пропущен. Файл собирается во временный путь рядом и заменяет существующий файл
+    /// только после успешной сборки, поэтому при ошибке старый файл остается на месте.
+    ///
+    /// Скачивание идет в рамках сессии: части, которые уже лежат в рабочей папке и совпадают
+    /// с контрольными суммами из VFS, не скачиваются, поэтому после ошибки повторный вызов
+    /// докачивает только недостающие части. Файл собирается, только когда проверены все части.
+    /// Скачанные в рамках сессии части и метафайл после сборки удаляются.
+    pub async fn async_download_file(
+        &self,
+        virtual_path: &Path,
+        destination: &Path,
+        conflicts: ConflictPolicy
+    ) -> Result<Option<PathBuf>, CloudError> {
+        self.async_download_file_with_context(virtual_path, destination, conflicts, &TransferContext::default()).await
+    }
+
+    /// Скачивание файла с отменой и ограничением скорости из `context`.
+    ///
+    /// При отмене останавливается передача частей и сборка файла, а скачанные в рамках
+    /// сессии части, недособранный файл и сессия скачивания удаляются.
+    pub async fn async_download_file_with_context(
+        &self,
+        virtual_path: &Path,
+        destination: &Path,
+        conflicts: ConflictPolicy,
+        context: &TransferContext
+    ) -> Result<Option<PathBuf>, CloudError> {
         use telegram_drive_file::file_assembly;
 
-        let v_fs = self.fs.borrow();
-        let v_file = v_fs.get_file(virtual_path)?;
+        let cancel = &context.cancel;
 
-        for part in &v_file.parts_name {
+        let v_file = self.get_file(virtual_path)?;
 
-            let part_path = format!("{}{}", self.option.work_dir.display(), part);
-            let _ = self.backend.download_file(Path::new(&part_path)).await.unwrap();
+        let target = if destination.is_dir() {
+            destination.join(folder_download::local_file_name(&v_file))
+        } else {
+            destination.to_path_buf()
+        };
+
+        let Some(target) = conflicts.resolve(&target)? else {
+            return Ok(None);
+        };
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
         }
-        let _ = self.backend.download_file(Path::new(&v_file.build_metafile)).await.unwrap();
 
-        let metafile_path = format!("{}{}", self.option.work_dir.display(), v_file.build_metafile);
+        let temporary = folder_download::temporary_path(&target);
 
-        //let metafile_path = format!("{}{}", self.option.work_dir.display(), v_file.build_metafile);
-        let output_file = file_assembly::decode_file(
-            &PathBuf::from(&metafile_path),
-            PathBuf::from(&self.option.work_dir)
-        ).unwrap();
+        let mut session = DownloadSession::load_or_new(&self.option.data_dir, &v_file);
 
-        self.save_vfs().unwrap();
+        let missing_files = v_file.parts_name
+            .iter()
+            .chain([&v_file.build_metafile])
+            .filter(|name| !session.is_verified(&v_file, name, &self.option.work_dir))
+            .map(|name| {
+                let size = v_file.metadata.checksums.get(name).map(|checksum| checksum.size).unwrap_or_default();
+                (name.clone(), size)
+            })
+            .collect::<Vec<_>>();
 
-        Ok(PathBuf::from(format!(
-            "{}{}.{}",
-            self.option.work_dir.display(),
-            v_file.name,
-            v_file.extension
-        )))
-    }
+        if missing_files.len() < v_file.parts_name.len() + 1 {
+            println!(
+                "{}: уже скачано файлов {} из {}",
+                virtual_path.display(),
+                v_file.parts_name.len() + 1 - missing_files.len(),
+                v_file.parts_name.len() + 1
+            );
+        }
 
-    fn add_file_to_vfs(&self, separation_file: &SeparationFile, virtual_path: &Path) -> Result<(), VFSError> {
-        let parts_name = separation_file.parts
+        let progress = FileProgress::new(&self.progress, virtual_path, TransferPhase::Download, v_file.parts_name
             .iter()
-            .map(|part| part.part_file_name.clone())
-            .collect::<Vec<String>>();
+            .chain([&v_file.build_metafile])
+            .map(|name| {
+                let total = v_file.metadata.checksums.get(name).map(|checksum| checksum.size).unwrap_or_default();
+                let done = if missing_files.iter().any(|(missing_name, _)| missing_name == name) { 0 } else { total };
+                (name.clone(), done, total)
+            })
+        );
+        let progress = &progress;
+
+        let result = async {
+            self.transfer_parts(&missing_files, TransferDirection::Download, context, |path| async move {
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+
+                self.backend
+                    .download_file_with_progress(&path, &|done, total| progress.update(&name, done, total), cancel)
+                    .await
+            }, |name, ()| {
+                if !session.record_download(&v_file, name, &self.option.work_dir) {
+                    let _ = fs::remove_file(self.option.work_dir.join(name));
+                    return Err(CloudError::ChecksumMismatch(name.to_owned()));
+                }
 
-        let metafile_name = separation_file.metafile.clone();
+                let size = fs::metadata(self.option.work_dir.join(name))?.len();
+                progress.update(name, size, size);
+
+                session.save(&self.option.data_dir).map_err(|e| e.into())
+            }).await?;
+
+            file_assembly::decode_file_to_with_progress(&self.option.work_dir.join(&v_file.build_metafile), &temporary, |done, total| {
+                let _ = self.progress.send(transfer_progress::file_event(virtual_path, TransferPhase::Join, done, total));
+            }, || cancel.is_cancelled())?;
+
+            folder_download::replace_target(&temporary, &target)?;
+
+            Ok::<(), CloudError>(())
+        }.await;
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temporary);
+        }
+
+        let is_cancelled = result.is_err() && cancel.is_cancelled();
+
+        // После отмены скачанные части не нужны, как и после сборки.
+        // После приостановки они остаются для продолжения скачивания
+        if result.is_ok() || (is_cancelled && !context.is_paused()) {
+            for name in &session.downloaded {
+                let _ = fs::remove_file(self.option.work_dir.join(name));
+            }
+            session.remove(&self.option.data_dir)?;
+        }
+
+        if is_cancelled {
+            return Err(CloudError::Cancelled);
+        }
+
+        result.map(|_| Some(target))
+    }
+
+    /// Добавление в VFS файла полностью загруженной сессии
+    fn add_file_to_vfs(&self, session: &UploadSession) -> Result<(), CloudError> {
+        let file_path = session.virtual_path.join(&session.filename);
+        if session.replace && self.fs.borrow().get_file(&file_path).is_ok() {
+            self.remove_file(&file_path)?;
+        }
 
         let v_file = VFSFile {
-            name: separation_file.filename.clone(),
-            extension: separation_file.file_extension.clone(),
-            build_metafile: metafile_name,
-            parts_name,
-            metadata: Default::default(),
+            name: session.filename.clone(),
+            extension: session.extension.clone(),
+            build_metafile: session.metafile.name.clone(),
+            parts_name: session.parts.iter().map(|part| part.name.clone()).collect(),
+            metadata: session.metadata()?,
         };
 
-        let res = self.fs.borrow_mut().add_file(virtual_path, v_file);
+        self.apply_operation(VFSOperation::AddFile {
+            path: session.virtual_path.clone(),
+            file: v_file,
+        })
+    }
 
-        self.save_vfs().unwrap();
+    pub fn remove_file(&self, path_file: &Path) -> Result<(), CloudError> {
+        self.apply_operation(VFSOperation::RemoveNode {
+            path: path_file.to_path_buf(),
+            clock: Default::default(),
+        })
+    }
 
-        return res;
+    pub fn remove_folder(&self, path_file: &Path) -> Result<(), CloudError> {
+        self.apply_operation(VFSOperation::RemoveNode {
+            path: path_file.to_path_buf(),
+            clock: Default::default(),
+        })
     }
 
-    pub fn remove_file(&self, path_file: &Path) -> Result<(), CloudError> {
-        let res = self.fs
-            .borrow_mut()
-            .remove_node(path_file)
-            .map_err(|e| e.into());
+    /// Переименование файла или папки внутри той же папки
+    pub fn rename_node(&self, path: &Path, name: &str) -> Result<(), CloudError> {
+        let mut node = self.fs.borrow().get_node(path)?.clone();
 
-        self.save_vfs().unwrap();
+        match &mut node {
+            FileSystemNode::File(file) => file.name = name.to_owned(),
+            FileSystemNode::Folder(folder) => folder.name = name.to_owned(),
+        }
 
-        return res;
+        self.apply_operation(VFSOperation::MoveNode {
+            path: path.to_path_buf(),
+            destination: path.parent().unwrap_or(path).to_path_buf(),
+            node,
+            clock: Default::default(),
+        })
     }
 
-    pub fn remove_folder(&self, path_file: &Path) -> Result<(), CloudError> {
-        let res = self.fs
-            .borrow_mut()
-            .remove_node(path_file)
-            .map_err(|e| e.into());
+    /// Перенос файла или папки в папку `destination`, отсутствующие папки создаются.
+    /// Перенос записывается в журнал одной операцией
+    pub fn move_node(&self, path: &Path, destination: &Path) -> Result<(), CloudError> {
+        vfs_persistence::check_move(path, destination)?;
+
+        let node = self.fs.borrow().get_node(path)?.clone();
 
-        self.save_vfs().unwrap();
+        self.create_folder(destination)?;
 
-        return res;
+        self.apply_operation(VFSOperation::MoveNode {
+            path: path.to_path_buf(),
+            destination: destination.to_path_buf(),
+            node,
+            clock: Default::default(),
+        })
     }
 }
 
 // META файл именуется одинаково (при загрузке одинаковых файлов идет перезапись meta файла)
+
+/// Чтение id устройства из `device_id`, при первом запуске id генерируется
+fn load_device_id(data_dir: &Path) -> io::Result<String> {
+    let device_id_path = data_dir.join("device_id");
+
+    match fs::read_to_string(&device_id_path) {
+        Ok(device_id) => Ok(device_id.trim().to_owned()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let device_id = uuid::Uuid::new_v4().simple().to_string();
+            fs::write(&device_id_path, &device_id)?;
+            Ok(device_id)
+        },
+        Err(e) => Err(e),
+    }
+}
@@ -1,5 +1,6 @@
 use std::path::{Path, PathBuf};
 use crate::cloud::CloudError; //DELETE
+use tokio_util::sync::CancellationToken;
 use crate::virtual_file_system::VFSFile;
 
 pub trait CloudBackend {
@@ -10,13 +11,64 @@ pub trait CloudBackend {
     fn close(self) -> Result<(), CloudError>;
 }
 
+/// Файл, хранящийся в облаке
+#[derive(Debug, Clone)]
+pub struct RemoteFile {
+    pub id: i64,
+    pub name: String,
+    pub size: u64,
+    /// Время загрузки (unix time)
+    pub date: i64,
+}
+
 #[async_trait::async_trait]
-pub trait AsyncCloudBackend {
+pub trait AsyncCloudBackend: Send + Sync {
     fn create() -> Self;
     async fn load_backend(&self) -> Result<(), CloudError>;
-    async fn upload_file(&self, file_path: &Path) -> Result<(), CloudError>;
+    /// Загрузка файла в облако, возвращает загруженный файл
+    async fn upload_file(&self, file_path: &Path) -> Result<RemoteFile, CloudError>;
     async fn download_file(&self, file_path: &Path) -> Result<(), CloudError>;
+    /// Загрузка с отчетом о прогрессе: `on_progress(загружено байт, размер файла)`.
+    /// При отмене через `cancel` загрузка останавливается с `CloudError::Cancelled`.
+    /// По умолчанию прогресс сообщается только по окончании загрузки, а отмена
+    /// проверяется только перед ее началом
+    async fn upload_file_with_progress(
+        &self,
+        file_path: &Path,
+        on_progress: &(dyn Fn(u64, u64) + Send + Sync),
+        cancel: &CancellationToken
+    ) -> Result<RemoteFile, CloudError> {
+        if cancel.is_cancelled() {
+            return Err(CloudError::Cancelled);
+        }
+
+        let remote_file = self.upload_file(file_path).await?;
+        on_progress(remote_file.size, remote_file.size);
+        Ok(remote_file)
+    }
+    /// Скачивание с отчетом о прогрессе: `on_progress(скачано байт, размер файла)`
+    async fn download_file_with_progress(
+        &self,
+        file_path: &Path,
+        _on_progress: &(dyn Fn(u64, u64) + Send + Sync),
+        cancel: &CancellationToken
+    ) -> Result<(), CloudError> {
+        if cancel.is_cancelled() {
+            return Err(CloudError::Cancelled);
+        }
+
+        self.download_file(file_path).await
+    }
     async fn remove_file(&self, file_path: &Path) -> Result<(), CloudError>;
+    /// Удаление одной копии файла из `list_files`. По умолчанию удаляется файл по имени
+    async fn remove_remote_file(&self, remote_file: &RemoteFile) -> Result<(), CloudError> {
+        self.remove_file(Path::new(&remote_file.name)).await
+    }
     async fn check_file(&self, file_name: &str) -> bool;
+    async fn list_files(&self) -> Result<Vec<RemoteFile>, CloudError>;
+    /// Закрепление файла, чтобы его было проще найти с другого устройства
+    async fn pin_file(&self, _file_name: &str) -> Result<(), CloudError> {
+        Ok(())
+    }
     async fn close(self) -> Result<(), CloudError>;
 }
\ No newline at end of file
@@ -0,0 +1,380 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use crate::cloud::{Cloud, CloudError};
+use crate::cloud_backend::AsyncCloudBackend;
+use crate::virtual_file_system::{Metadata, VFSFile};
+
+/// Что делать с символическими ссылками
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Загружать то, на что указывает ссылка
+    Follow,
+    #[default]
+    Skip,
+    /// Сохранять в VFS только путь цели ссылки
+    StoreAsLink,
+}
+
+impl SymlinkPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "follow" => Some(Self::Follow),
+            "skip" => Some(Self::Skip),
+            "link" => Some(Self::StoreAsLink),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DirectoryUploadOptions {
+    /// Glob шаблоны путей относительно загружаемой папки, пустой список - все файлы
+    pub include: Vec<String>,
+    /// Glob шаблоны исключаемых файлов и папок
+    pub exclude: Vec<String>,
+    pub symlinks: SymlinkPolicy,
+}
+
+/// Состояние загрузки папки, передается после каждого файла
+#[derive(Debug, Clone)]
+pub struct DirectoryUploadProgress {
+    pub current: PathBuf,
+    pub files_done: usize,
+    pub files_total: usize,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct DirectoryUploadReport {
+    pub uploaded: Vec<PathBuf>,
+    /// Ссылки, сохраненные без содержимого
+    pub links: Vec<PathBuf>,
+    /// Файлы, отброшенные шаблонами или политикой ссылок
+    pub skipped: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, String)>,
+    pub uploaded_bytes: u64,
+}
+
+/// Файл, найденный при обходе папки
+#[derive(Debug)]
+enum Entry {
+    File { path: PathBuf, virtual_folder: PathBuf, size: u64 },
+    Link { path: PathBuf, virtual_folder: PathBuf, target: PathBuf },
+}
+
+impl Entry {
+    fn path(&self) -> &Path {
+        match self {
+            Entry::File { path, .. } | Entry::Link { path, .. } => path,
+        }
+    }
+
+    /// Путь в VFS, файлы в VFS называются без расширения
+    fn virtual_path(&self) -> PathBuf {
+        match self {
+            Entry::File { path, virtual_folder, .. } | Entry::Link { path, virtual_folder, .. } => {
+                virtual_folder.join(path.file_stem().unwrap_or_default())
+            }
+        }
+    }
+}
+
+/// Рекурсивная загрузка содержимого `local_dir` в папку VFS `virtual_path`.
+///
+/// Структура папок повторяется в VFS, включая пустые папки. Ошибка загрузки одного
+/// файла попадает в отчет и не прерывает загрузку остальных. Файлы одной папки,
+/// различающиеся только расширением, попадают в отчет до начала загрузки.
+pub async fn upload_directory<T: AsyncCloudBackend>(
+    cloud: &Cloud<T>,
+    local_dir: &Path,
+    virtual_path: &Path,
+    options: &DirectoryUploadOptions,
+    mut on_progress: impl FnMut(&DirectoryUploadProgress)
+) -> Result<DirectoryUploadReport, CloudError> {
+
+    let include = build_glob_set(&options.include)?;
+    let exclude = build_glob_set(&options.exclude)?;
+
+    let mut report = DirectoryUploadReport::default();
+    let mut entries = vec![];
+    let mut folders = vec![];
+    let mut visited = HashSet::from([fs::canonicalize(local_dir)?]);
+
+    let mut walker = Walker {
+        root: local_dir,
+        include: (!options.include.is_empty()).then_some(&include),
+        exclude: &exclude,
+        symlinks: options.symlinks,
+        visited: &mut visited,
+        entries: &mut entries,
+        folders: &mut folders,
+        report: &mut report,
+    };
+    walker.walk(local_dir, virtual_path);
+
+    let entries = reject_name_collisions(entries, &mut report);
+
+    let files_total = entries.len();
+    let bytes_total = entries
+        .iter()
+        .map(|entry| match entry {
+            Entry::File { size, .. } => *size,
+            Entry::Link { .. } => 0,
+        })
+        .sum();
+
+    let mut progress = DirectoryUploadProgress {
+        current: PathBuf::new(),
+        files_done: 0,
+        files_total,
+        bytes_done: 0,
+        bytes_total,
+    };
+
+    for (path, virtual_folder) in folders {
+        if let Err(e) = cloud.create_folder(&virtual_folder) {
+            report.failed.push((path, format!("{:?}", e)));
+        }
+    }
+
+    for entry in entries {
+        match entry {
+            Entry::File { path, virtual_folder, size } => {
+                let result = match cloud.create_folder(&virtual_folder) {
+                    Ok(()) => cloud.async_upload_file(&path, &virtual_folder).await,
+                    Err(e) => Err(e),
+                };
+
+                match result {
+                    Ok(()) => {
+                        report.uploaded_bytes += size;
+                        report.uploaded.push(path.clone());
+                    },
+                    Err(e) => report.failed.push((path.clone(), format!("{:?}", e))),
+                }
+
+                progress.bytes_done += size;
+                progress.current = path;
+            },
+
+            Entry::Link { path, virtual_folder, target } => {
+                match add_link(cloud, &path, &virtual_folder, target) {
+                    Ok(()) => report.links.push(path.clone()),
+                    Err(e) => report.failed.push((path.clone(), format!("{:?}", e))),
+                }
+
+                progress.current = path;
+            }
+        }
+
+        progress.files_done += 1;
+        on_progress(&progress);
+    }
+
+    Ok(report)
+}
+
+/// В VFS файлы различаются только по имени без расширения, поэтому `foo.c` и `foo.h`
+/// одной папки заняли бы одно место. Такие файлы не загружаются
+fn reject_name_collisions(entries: Vec<Entry>, report: &mut DirectoryUploadReport) -> Vec<Entry> {
+    let mut counts = HashMap::new();
+
+    for entry in &entries {
+        *counts.entry(entry.virtual_path()).or_insert(0) += 1;
+    }
+
+    entries
+        .into_iter()
+        .filter(|entry| {
+            if counts[&entry.virtual_path()] == 1 {
+                return true;
+            }
+
+            report.failed.push((
+                entry.path().to_path_buf(),
+                format!("В папке есть другой файл с именем {:?}", entry.path().file_stem().unwrap_or_default())
+            ));
+            false
+        })
+        .collect()
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+
+    builder.build()
+}
+
+/// Ссылка хранится в VFS как файл без частей
+fn add_link<T: AsyncCloudBackend>(
+    cloud: &Cloud<T>,
+    path: &Path,
+    virtual_folder: &Path,
+    target: PathBuf
+) -> Result<(), CloudError> {
+
+    cloud.create_folder(virtual_folder)?;
+
+    let mut metadata = Metadata::default();
+    metadata.record_attributes(&fs::symlink_metadata(path)?);
+    metadata.symlink = Some(target);
+
+    cloud.add_file(virtual_folder, VFSFile {
+        name: path.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+        extension: path.extension().unwrap_or_default().to_string_lossy().to_string(),
+        build_metafile: String::new(),
+        parts_name: vec![],
+        metadata,
+    })
+}
+
+struct Walker<'a> {
+    root: &'a Path,
+    include: Option<&'a GlobSet>,
+    exclude: &'a GlobSet,
+    symlinks: SymlinkPolicy,
+    /// Уже обойденные папки, защищают от циклов при переходе по ссылкам
+    visited: &'a mut HashSet<PathBuf>,
+    entries: &'a mut Vec<Entry>,
+    /// Обойденные папки и соответствующие им папки VFS
+    folders: &'a mut Vec<(PathBuf, PathBuf)>,
+    report: &'a mut DirectoryUploadReport,
+}
+
+impl Walker<'_> {
+
+    fn walk(&mut self, dir: &Path, virtual_folder: &Path) {
+
+        let dir_entries = match fs::read_dir(dir) {
+            Ok(dir_entries) => dir_entries,
+            Err(e) => {
+                self.report.failed.push((dir.to_path_buf(), e.to_string()));
+                return;
+            }
+        };
+
+        if dir == self.root || self.is_included(dir.strip_prefix(self.root).unwrap()) {
+            self.folders.push((dir.to_path_buf(), virtual_folder.to_path_buf()));
+        }
+
+        let mut paths = dir_entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        for path in paths {
+            let relative_path = path.strip_prefix(self.root).unwrap();
+
+            if self.exclude.is_match(relative_path) {
+                self.report.skipped.push(path);
+                continue;
+            }
+
+            let symlink_metadata = match fs::symlink_metadata(&path) {
+                Ok(symlink_metadata) => symlink_metadata,
+                Err(e) => {
+                    self.report.failed.push((path, e.to_string()));
+                    continue;
+                }
+            };
+
+            let is_symlink = symlink_metadata.file_type().is_symlink();
+
+            if is_symlink && self.symlinks != SymlinkPolicy::Follow {
+                if self.symlinks == SymlinkPolicy::Skip || !self.is_included(relative_path) {
+                    self.report.skipped.push(path);
+                    continue;
+                }
+
+                match fs::read_link(&path) {
+                    Ok(target) => self.entries.push(Entry::Link {
+                        path,
+                        virtual_folder: virtual_folder.to_path_buf(),
+                        target,
+                    }),
+                    Err(e) => self.report.failed.push((path, e.to_string())),
+                }
+                continue;
+            }
+
+            // Для ссылок берутся метаданные цели
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    self.report.failed.push((path, e.to_string()));
+                    continue;
+                }
+            };
+
+            if metadata.is_dir() {
+                match fs::canonicalize(&path).map(|canonical_path| self.visited.insert(canonical_path)) {
+                    Ok(true) => {
+                        let child_folder = virtual_folder.join(path.file_name().unwrap());
+                        self.walk(&path, &child_folder);
+                    },
+                    Ok(false) => self.report.skipped.push(path),
+                    Err(e) => self.report.failed.push((path, e.to_string())),
+                }
+                continue;
+            }
+
+            if !self.is_included(relative_path) {
+                self.report.skipped.push(path);
+                continue;
+            }
+
+            self.entries.push(Entry::File {
+                path,
+                virtual_folder: virtual_folder.to_path_buf(),
+                size: metadata.len(),
+            });
+        }
+    }
+
+    fn is_included(&self, relative_path: &Path) -> bool {
+        self.include.is_none_or(|include| include.is_match(relative_path))
+    }
+}
+
+impl Display for DirectoryUploadProgress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}/{}] {}/{} байт - {}",
+            self.files_done,
+            self.files_total,
+            self.bytes_done,
+            self.bytes_total,
+            self.current.display()
+        )
+    }
+}
+
+impl Display for DirectoryUploadReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Загружено файлов: {} ({} байт)", self.uploaded.len(), self.uploaded_bytes)?;
+
+        if !self.links.is_empty() {
+            writeln!(f, "Сохранено ссылок: {}", self.links.len())?;
+        }
+
+        writeln!(f, "Пропущено: {}", self.skipped.len())?;
+
+        writeln!(f, "Ошибок: {}", self.failed.len())?;
+        for (path, error) in &self.failed {
+            writeln!(f, "  ! {} - {}", path.display(), error)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use notify::event::{ModifyKind, RenameMode};
+
+use crate::cloud::{Cloud, CloudError};
+use crate::cloud_backend::AsyncCloudBackend;
+use crate::folder_download;
+use crate::virtual_file_system::Checksum;
+
+/// Папка VFS, в которую попадают удаленные файлы при `DeletionPolicy::Trash`
+pub const TRASH_FOLDER: &str = "fs://.trash";
+
+/// Что делать в VFS, когда файл удален из отслеживаемой папки
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeletionPolicy {
+    /// Удалить файл и из VFS
+    Mirror,
+    /// Перенести файл в корзину VFS
+    #[default]
+    Trash,
+    Ignore,
+}
+
+impl DeletionPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "mirror" => Some(Self::Mirror),
+            "trash" => Some(Self::Trash),
+            "ignore" => Some(Self::Ignore),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// Сколько файл должен не меняться, прежде чем он будет загружен
+    pub debounce: Duration,
+    pub deletions: DeletionPolicy,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            debounce: Duration::from_secs(2),
+            deletions: DeletionPolicy::default(),
+        }
+    }
+}
+
+/// Действие, выполненное по изменению в отслеживаемой папке
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchAction {
+    Uploaded(PathBuf),
+    /// Содержимое совпадает с уже загруженным
+    Unchanged(PathBuf),
+    Removed(PathBuf),
+    Trashed { path: PathBuf, trash: PathBuf },
+    Failed(PathBuf, String),
+}
+
+/// Изменившийся файл, ожидающий окончания записи
+#[derive(Debug)]
+struct PendingChange {
+    last_event: Instant,
+    size: Option<u64>,
+}
+
+/// Автоматическая загрузка изменений локальной папки в папку VFS
+pub struct DirectoryWatcher<'a, T: AsyncCloudBackend> {
+    cloud: &'a Cloud<T>,
+    local_dir: PathBuf,
+    virtual_root: PathBuf,
+    options: WatchOptions,
+    pending: HashMap<PathBuf, PendingChange>,
+    removed: Vec<PathBuf>,
+}
+
+impl<'a, T: AsyncCloudBackend> DirectoryWatcher<'a, T> {
+
+    pub fn new(cloud: &'a Cloud<T>, local_dir: &Path, virtual_root: &Path, options: WatchOptions) -> Self {
+        Self {
+            cloud,
+            local_dir: local_dir.to_path_buf(),
+            virtual_root: virtual_root.to_path_buf(),
+            options,
+            pending: HashMap::new(),
+            removed: vec![],
+        }
+    }
+
+    /// Отслеживание папки, пока не завершится `shutdown`
+    pub async fn run(mut self, shutdown: impl Future<Output = ()>) -> Result<(), CloudError> {
+
+        let (event_sender, mut event_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = event_sender.send(event);
+        })?;
+        watcher.watch(&self.local_dir, RecursiveMode::Recursive)?;
+
+        println!("Отслеживается {} -> {}", self.local_dir.display(), self.virtual_root.display());
+
+        let mut tick = tokio::time::interval((self.options.debounce / 2).max(Duration::from_millis(100)));
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                Some(event) = event_receiver.recv() => match event {
+                    Ok(event) => self.handle_event(&event, Instant::now()),
+                    Err(e) => println!("Ошибка отслеживания: {:?}", e),
+                },
+                _ = tick.tick() => {
+                    for action in self.process(Instant::now()).await {
+                        println!("{}", action);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Учет события файловой системы, действия откладываются до `process`
+    pub fn handle_event(&mut self, event: &Event, now: Instant) {
+        match event.kind {
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Metadata(_) | ModifyKind::Any) =>
+                event.paths.iter().for_each(|path| self.changed(path, now)),
+
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) =>
+                event.paths.iter().for_each(|path| self.deleted(path)),
+
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                self.deleted(&event.paths[0]);
+                self.changed(&event.paths[1], now);
+            },
+
+            EventKind::Modify(ModifyKind::Name(_)) => {
+                for path in &event.paths {
+                    if fs::symlink_metadata(path).is_ok() {
+                        self.changed(path, now);
+                    } else {
+                        self.deleted(path);
+                    }
+                }
+            },
+
+            _ => {}
+        }
+    }
+
+    /// Загрузка файлов, запись которых закончилась, и обработка удалений
+    pub async fn process(&mut self, now: Instant) -> Vec<WatchAction> {
+
+        let mut actions = vec![];
+
+        for path in std::mem::take(&mut self.removed) {
+            if let Some(action) = self.apply_deletion(&path) {
+                actions.push(action);
+            }
+        }
+
+        let mut settled = vec![];
+
+        for (path, change) in self.pending.iter_mut() {
+            if now.duration_since(change.last_event) < self.options.debounce {
+                continue;
+            }
+
+            // Файл еще пишется, если его размер изменился с прошлой проверки
+            let size = fs::metadata(path).ok().map(|metadata| metadata.len());
+            if size != change.size {
+                change.size = size;
+                change.last_event = now;
+                continue;
+            }
+
+            settled.push(path.clone());
+        }
+
+        settled.sort();
+
+        for path in settled {
+            self.pending.remove(&path);
+
+            if path.is_file() {
+                actions.push(self.upload(&path).await);
+            }
+        }
+
+        actions
+    }
+
+    fn changed(&mut self, path: &Path, now: Instant) {
+        if path.is_dir() {
+            // Файлы, созданные до появления папки в отслеживании, событий не дают
+            if let Ok(entries) = fs::read_dir(path) {
+                for entry in entries.flatten() {
+                    self.changed(&entry.path(), now);
+                }
+            }
+            return;
+        }
+
+        let size = fs::metadata(path).ok().map(|metadata| metadata.len());
+
+        self.pending.insert(path.to_path_buf(), PendingChange { last_event: now, size });
+    }
+
+    fn deleted(&mut self, path: &Path) {
+        self.pending.remove(path);
+        self.removed.push(path.to_path_buf());
+    }
+
+    async fn upload(&self, path: &Path) -> WatchAction {
+        let virtual_path = self.virtual_path(path, true);
+        let virtual_folder = virtual_path.parent().unwrap();
+
+        let result = async {
+            let content = Checksum::of_file(path)?;
+
+            if let Ok(uploaded_file) = self.cloud.get_file(&virtual_path) {
+                // Файлы VFS называются без расширения, файл с другим расширением не заменяется
+                let uploaded_name = folder_download::local_file_name(&uploaded_file);
+                if path.file_name() != Some(uploaded_name.as_ref()) {
+                    return Err(CloudError::NameCollision(vec![path.to_path_buf(), path.with_file_name(uploaded_name)]));
+                }
+
+                if uploaded_file.metadata.content.as_ref() == Some(&content) {
+                    return Ok(false);
+                }
+            }
+
+            // Загруженная версия заменяет старую только после загрузки
+            self.cloud.create_folder(virtual_folder)?;
+            self.cloud.async_replace_file(&path.to_path_buf(), virtual_folder).await?;
+
+            Ok::<bool, CloudError>(true)
+        }.await;
+
+        match result {
+            Ok(true) => WatchAction::Uploaded(path.to_path_buf()),
+            Ok(false) => WatchAction::Unchanged(path.to_path_buf()),
+            Err(e) => WatchAction::Failed(path.to_path_buf(), format!("{:?}", e)),
+        }
+    }
+
+    fn apply_deletion(&self, path: &Path) -> Option<WatchAction> {
+        if self.options.deletions == DeletionPolicy::Ignore {
+            return None;
+        }
+
+        // Удаленный путь мог быть как файлом, так и папкой. Файл VFS называется без расширения,
+        // поэтому он относится к удаленному файлу, только если расширения совпадают
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        let file_path = self.virtual_path(path, true);
+        let folder_path = self.virtual_path(path, false);
+
+        let virtual_path = if self.cloud.get_file(&file_path).is_ok_and(|file| file.extension == extension) {
+            file_path
+        } else if self.cloud.get_folder(&folder_path).is_ok() {
+            folder_path
+        } else {
+            return None;
+        };
+
+        let result = match self.options.deletions {
+            DeletionPolicy::Mirror => self.cloud
+                .remove_file(&virtual_path)
+                .map(|_| WatchAction::Removed(path.to_path_buf())),
+
+            DeletionPolicy::Trash => {
+                let relative_folder = path
+                    .strip_prefix(&self.local_dir)
+                    .ok()
+                    .and_then(Path::parent)
+                    .unwrap_or(Path::new(""));
+
+                let trash = Path::new(TRASH_FOLDER)
+                    .join(Utc::now().format("%Y-%m-%d_%H-%M-%S").to_string())
+                    .join(relative_folder);
+
+                self.cloud
+                    .move_node(&virtual_path, &trash)
+                    .map(|_| WatchAction::Trashed { path: path.to_path_buf(), trash })
+            },
+
+            DeletionPolicy::Ignore => unreachable!(),
+        };
+
+        Some(result.unwrap_or_else(|e| WatchAction::Failed(path.to_path_buf(), format!("{:?}", e))))
+    }
+
+    /// Путь в VFS для локального пути, файлы VFS называются без расширения
+    fn virtual_path(&self, path: &Path, is_file: bool) -> PathBuf {
+        let relative_path = path.strip_prefix(&self.local_dir).unwrap_or(path);
+
+        let name = if is_file {
+            relative_path.file_stem().unwrap_or_default()
+        } else {
+            relative_path.file_name().unwrap_or_default()
+        };
+
+        match relative_path.parent() {
+            Some(parent) => self.virtual_root.join(parent).join(name),
+            None => self.virtual_root.join(name),
+        }
+    }
+}
+
+impl Display for WatchAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchAction::Uploaded(path) => write!(f, "> {} загружен", path.display()),
+            WatchAction::Unchanged(path) => write!(f, "  {} не изменился", path.display()),
+            WatchAction::Removed(path) => write!(f, "- {} удален из VFS", path.display()),
+            WatchAction::Trashed { path, trash } =>
+                write!(f, "- {} перенесен в {}", path.display(), trash.display()),
+            WatchAction::Failed(path, error) => write!(f, "! {} - {}", path.display(), error),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::virtual_file_system::{Checksum, VFSFile};
+
+/// Папка с сессиями незавершенных скачиваний внутри папки данных
+const SESSIONS_DIR: &str = "download_sessions";
+
+/// Незавершенное скачивание файла.
+///
+/// Сессия помнит, какие части уже скачаны и проверены, поэтому после ошибки или
+/// перезапуска скачиваются только недостающие части. Сессия привязана к метафайлу,
+/// то есть к конкретной версии файла.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DownloadSession {
+    pub metafile: String,
+    /// Файлы, скачанные в рамках сессии. После сборки они удаляются,
+    /// локальные копии, которые были в рабочей папке до скачивания, остаются
+    pub downloaded: BTreeSet<String>,
+    /// Файлы, контрольная сумма которых совпала с записанной в VFS
+    pub verified: BTreeSet<String>,
+}
+
+impl DownloadSession {
+
+    /// Сохраненная сессия скачивания версии файла или новая сессия
+    pub fn load_or_new(data_dir: &Path, file: &VFSFile) -> Self {
+        fs::read(Self::path(data_dir, &file.build_metafile))
+            .ok()
+            .and_then(|session| serde_json::from_slice(&session).ok())
+            .unwrap_or_else(|| Self {
+                metafile: file.build_metafile.clone(),
+                ..Self::default()
+            })
+    }
+
+    pub fn save(&self, data_dir: &Path) -> io::Result<()> {
+        let session_path = Self::path(data_dir, &self.metafile);
+        let tmp_path = session_path.with_extension("tmp");
+
+        fs::create_dir_all(session_path.parent().unwrap())?;
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(tmp_path, session_path)
+    }
+
+    pub fn remove(&self, data_dir: &Path) -> io::Result<()> {
+        match fs::remove_file(Self::path(data_dir, &self.metafile)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Часть уже лежит в рабочей папке и совпадает с записанной в VFS.
+    /// Части без контрольной суммы проверить нельзя, они скачиваются заново
+    pub fn is_verified(&mut self, file: &VFSFile, name: &str, work_dir: &Path) -> bool {
+        let Some(checksum) = file.metadata.checksums.get(name) else {
+            return false;
+        };
+
+        let path = work_dir.join(name);
+
+        // Проверенная при прошлом запуске часть не перечитывается, если не изменился размер
+        if self.verified.contains(name) && fs::metadata(&path).is_ok_and(|metadata| metadata.len() == checksum.size) {
+            return true;
+        }
+
+        let is_verified = Checksum::of_file(&path).is_ok_and(|actual| &actual == checksum);
+
+        if is_verified {
+            self.verified.insert(name.to_owned());
+        } else {
+            self.verified.remove(name);
+        }
+
+        is_verified
+    }
+
+    /// Учет скачанной части, `false` - часть повреждена и в сессию не попала
+    pub fn record_download(&mut self, file: &VFSFile, name: &str, work_dir: &Path) -> bool {
+        let is_valid = !file.metadata.checksums.contains_key(name) || self.is_verified(file, name, work_dir);
+
+        if is_valid {
+            self.downloaded.insert(name.to_owned());
+        }
+
+        is_valid
+    }
+
+    fn path(data_dir: &Path, metafile: &str) -> PathBuf {
+        data_dir.join(SESSIONS_DIR).join(format!("{}.json", metafile))
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cloud::{Cloud, CloudError};
+use crate::cloud_backend::AsyncCloudBackend;
+use crate::virtual_file_system::{FileSystemNode, Metadata, VFSFile, VFSFolder};
+
+/// Файл состояния незавершенного скачивания в папке назначения
+const DOWNLOAD_STATE_FILE: &str = ".telegram_drive_download";
+
+/// Что делать, если файл в папке назначения уже существует
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ConflictPolicy {
+    Overwrite,
+    #[default]
+    Skip,
+    /// Сохранить рядом под именем `имя (n).расширение`
+    Rename,
+}
+
+impl ConflictPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "overwrite" => Some(Self::Overwrite),
+            "skip" => Some(Self::Skip),
+            "rename" => Some(Self::Rename),
+            _ => None,
+        }
+    }
+
+    /// Путь, по которому нужно сохранить файл, `None` - файл сохранять не нужно.
+    ///
+    /// При `Overwrite` существующий файл не удаляется: файл сохраняется во временный путь
+    /// и заменяет его через `replace_target` только после успешного скачивания
+    pub fn resolve(&self, target: &Path) -> io::Result<Option<PathBuf>> {
+        if fs::symlink_metadata(target).is_err() {
+            return Ok(Some(target.to_path_buf()));
+        }
+
+        match self {
+            ConflictPolicy::Skip => Ok(None),
+            ConflictPolicy::Overwrite => Ok(Some(target.to_path_buf())),
+            ConflictPolicy::Rename => Ok(Some(free_path(target))),
+        }
+    }
+}
+
+/// Временный путь рядом с `target`, куда сохраняется файл до замены `target`
+pub fn temporary_path(target: &Path) -> PathBuf {
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    target.with_file_name(format!(".{}.download", name))
+}
+
+/// Замена `target` сохраненным во временный путь файлом
+pub fn replace_target(temporary: &Path, target: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(target).is_ok_and(|metadata| metadata.is_dir()) {
+        fs::remove_dir_all(target)?;
+    }
+
+    fs::rename(temporary, target)
+}
+
+#[derive(Debug, Clone)]
+pub struct FolderDownloadOptions {
+    pub conflicts: ConflictPolicy,
+    /// Восстанавливать время изменения и права доступа файлов
+    pub restore_metadata: bool,
+}
+
+impl Default for FolderDownloadOptions {
+    fn default() -> Self {
+        Self {
+            conflicts: ConflictPolicy::default(),
+            restore_metadata: true,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FolderDownloadReport {
+    pub downloaded: Vec<PathBuf>,
+    /// Файлы, скачанные при прошлом запуске
+    pub resumed: Vec<PathBuf>,
+    /// Файлы, пропущенные из-за конфликта
+    pub skipped: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// Уже скачанные файлы: путь относительно папки назначения и метафайл версии
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DownloadState {
+    completed: BTreeMap<String, String>,
+}
+
+impl DownloadState {
+    fn load(local_dest: &Path) -> Self {
+        fs::read(local_dest.join(DOWNLOAD_STATE_FILE))
+            .ok()
+            .and_then(|state| serde_json::from_slice(&state).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, local_dest: &Path) -> io::Result<()> {
+        let state_path = local_dest.join(DOWNLOAD_STATE_FILE);
+        let tmp_path = state_path.with_extension("tmp");
+
+        fs::write(&tmp_path, serde_json::to_vec(self)?)?;
+        fs::rename(tmp_path, state_path)
+    }
+}
+
+/// Рекурсивное скачивание папки VFS `virtual_path` в локальную папку `local_dest`.
+///
+/// Структура папок воссоздается на диске. Скачанные файлы отмечаются в файле состояния,
+/// поэтому повторный запуск после прерывания продолжает с первого нескачанного файла.
+/// Файл состояния удаляется, когда скачаны все файлы.
+pub async fn download_folder<T: AsyncCloudBackend>(
+    cloud: &Cloud<T>,
+    virtual_path: &Path,
+    local_dest: &Path,
+    options: &FolderDownloadOptions
+) -> Result<FolderDownloadReport, CloudError> {
+
+    let folder = cloud.get_folder(virtual_path)?;
+
+    let mut files = vec![];
+    collect_files(&folder, Path::new(""), local_dest, &mut files)?;
+
+    let mut state = DownloadState::load(local_dest);
+    let mut report = FolderDownloadReport::default();
+
+    for (relative_path, file) in files {
+        let target = local_dest.join(&relative_path);
+        let state_key = relative_path.to_string_lossy().to_string();
+
+        if state.completed.get(&state_key) == Some(&file.build_metafile)
+            && fs::symlink_metadata(&target).is_ok()
+        {
+            report.resumed.push(target);
+            continue;
+        }
+
+        let virtual_file_path = virtual_path
+            .join(relative_path.parent().unwrap())
+            .join(&file.name);
+
+        match download_to(cloud, &virtual_file_path, &file, &target, options).await {
+            Ok(Some(saved_path)) => report.downloaded.push(saved_path),
+            Ok(None) => report.skipped.push(target),
+            Err(e) => {
+                report.failed.push((target, format!("{:?}", e)));
+                continue;
+            }
+        }
+
+        state.completed.insert(state_key, file.build_metafile.clone());
+        state.save(local_dest)?;
+    }
+
+    if report.failed.is_empty() {
+        let _ = fs::remove_file(local_dest.join(DOWNLOAD_STATE_FILE));
+    }
+
+    Ok(report)
+}
+
+/// Локальное имя файла VFS
+pub fn local_file_name(file: &VFSFile) -> String {
+    if file.extension.is_empty() {
+        file.name.clone()
+    } else {
+        format!("{}.{}", file.name, file.extension)
+    }
+}
+
+/// Восстановление времени изменения и прав доступа файла
+pub fn restore_metadata(path: &Path, metadata: &Metadata) -> io::Result<()> {
+    if let Some(modified) = metadata.modified {
+        fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(UNIX_EPOCH + Duration::from_secs(modified))?;
+    }
+
+    #[cfg(unix)]
+    if let Some(permissions) = metadata.permissions {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(permissions))?;
+    }
+
+    Ok(())
+}
+
+/// Все файлы папки с путями относительно нее, папки сразу создаются на диске
+fn collect_files(
+    folder: &VFSFolder,
+    relative_path: &Path,
+    local_dest: &Path,
+    output: &mut Vec<(PathBuf, VFSFile)>
+) -> io::Result<()> {
+
+    fs::create_dir_all(local_dest.join(relative_path))?;
+
+    let mut children = folder.children.values().collect::<Vec<_>>();
+    children.sort_by_key(|node| node.name().to_owned());
+
+    for node in children {
+        match node {
+            FileSystemNode::File(file) =>
+                output.push((relative_path.join(local_file_name(file)), file.clone())),
+            FileSystemNode::Folder(child_folder) =>
+                collect_files(child_folder, &relative_path.join(&child_folder.name), local_dest, output)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Скачивание одного файла, возвращает путь, по которому он сохранен
+async fn download_to<T: AsyncCloudBackend>(
+    cloud: &Cloud<T>,
+    virtual_path: &Path,
+    file: &VFSFile,
+    target: &Path,
+    options: &FolderDownloadOptions
+) -> Result<Option<PathBuf>, CloudError> {
+
+    if let Some(link_target) = &file.metadata.symlink {
+        let Some(target) = options.conflicts.resolve(target)? else {
+            return Ok(None);
+        };
+
+        let temporary = temporary_path(&target);
+        let _ = fs::remove_file(&temporary);
+
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(link_target, &temporary)?;
+        #[cfg(not(unix))]
+        return Err(io::Error::new(io::ErrorKind::Unsupported, format!("ссылка на {}", link_target.display())).into());
+
+        if let Err(e) = replace_target(&temporary, &target) {
+            let _ = fs::remove_file(&temporary);
+            return Err(e.into());
+        }
+
+        return Ok(Some(target));
+    }
+
+    let Some(target) = cloud.async_download_file(virtual_path, target, options.conflicts).await? else {
+        return Ok(None);
+    };
+
+    if options.restore_metadata {
+        restore_metadata(&target, &file.metadata)?;
+    }
+
+    Ok(Some(target))
+}
+
+/// Первый свободный путь вида `имя (n).расширение`
+fn free_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let extension = path.extension().map(|extension| format!(".{}", extension.to_string_lossy()));
+
+    (1..)
+        .map(|index| path.with_file_name(format!("{} ({}){}", stem, index, extension.as_deref().unwrap_or(""))))
+        .find(|candidate| fs::symlink_metadata(candidate).is_err())
+        .unwrap()
+}
+
+impl Display for FolderDownloadReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Скачано файлов: {}", self.downloaded.len())?;
+
+        if !self.resumed.is_empty() {
+            writeln!(f, "Скачано при прошлом запуске: {}", self.resumed.len())?;
+        }
+
+        writeln!(f, "Пропущено из-за конфликтов: {}", self.skipped.len())?;
+
+        writeln!(f, "Ошибок: {}", self.failed.len())?;
+        for (path, error) in &self.failed {
+            writeln!(f, "  ! {} - {}", path.display(), error)?;
+        }
+
+        Ok(())
+    }
+}
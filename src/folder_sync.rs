@@ -0,0 +1,467 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cloud::{Cloud, CloudError};
+use crate::cloud_backend::AsyncCloudBackend;
+use crate::folder_download::{self, ConflictPolicy};
+use crate::virtual_file_system::{modified_secs, Checksum, FileSystemNode, VFSFile, VFSFolder};
+
+/// База состояния синхронизации в синхронизируемой папке
+const SYNC_STATE_FILE: &str = ".telegram_drive_sync";
+
+/// Состояние файла после последней синхронизации
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SyncedFile {
+    size: u64,
+    modified: Option<u64>,
+    md5: String,
+    /// Метафайл версии в VFS
+    build_metafile: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SyncState {
+    /// Папка VFS, с которой синхронизирована локальная папка
+    virtual_root: PathBuf,
+    /// Файлы по пути относительно синхронизируемой папки
+    files: BTreeMap<String, SyncedFile>,
+    /// Пути, действие над которыми при прошлой синхронизации не завершилось
+    #[serde(default)]
+    unfinished: BTreeSet<String>,
+}
+
+impl SyncState {
+    fn load(local_dir: &Path, virtual_root: &Path) -> Self {
+        fs::read(local_dir.join(SYNC_STATE_FILE))
+            .ok()
+            .and_then(|state| serde_json::from_slice::<SyncState>(&state).ok())
+            // Состояние другой пары папок не подходит
+            .filter(|state| state.virtual_root == virtual_root)
+            .unwrap_or_else(|| SyncState {
+                virtual_root: virtual_root.to_path_buf(),
+                files: BTreeMap::new(),
+                unfinished: BTreeSet::new(),
+            })
+    }
+
+    fn save(&self, local_dir: &Path) -> io::Result<()> {
+        let state_path = local_dir.join(SYNC_STATE_FILE);
+        let tmp_path = state_path.with_extension("tmp");
+
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(tmp_path, state_path)
+    }
+}
+
+/// Действие синхронизации над файлом по относительному пути
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncAction {
+    Upload(String),
+    Download(String),
+    DeleteLocal(String),
+    DeleteRemote(String),
+    /// Файл изменен с обеих сторон: локальная версия сохраняется под именем `copy`,
+    /// на ее место скачивается версия из облака
+    Conflict { path: String, copy: String },
+    /// Файл удален с обеих сторон, остается забыть его состояние
+    Forget(String),
+    /// Файлы отличаются только расширением, а файлы VFS называются без расширения.
+    /// Такие файлы не синхронизируются, пока их не переименуют
+    NameCollision(Vec<String>),
+}
+
+impl SyncAction {
+    /// Путь файла, состояние которого меняет действие
+    fn path(&self) -> Option<&str> {
+        match self {
+            SyncAction::Upload(path)
+            | SyncAction::Download(path)
+            | SyncAction::DeleteLocal(path)
+            | SyncAction::DeleteRemote(path)
+            | SyncAction::Conflict { path, .. }
+            | SyncAction::Forget(path) => Some(path),
+            SyncAction::NameCollision(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncPlan {
+    pub actions: Vec<SyncAction>,
+}
+
+impl SyncPlan {
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub applied: Vec<SyncAction>,
+    pub failed: Vec<(SyncAction, String)>,
+}
+
+/// Локальный файл при сканировании
+#[derive(Debug)]
+struct LocalFile {
+    size: u64,
+    modified: Option<u64>,
+    md5: String,
+}
+
+/// Двусторонняя синхронизация локальной папки и папки VFS
+pub struct FolderSync<'a, T: AsyncCloudBackend> {
+    cloud: &'a Cloud<T>,
+    local_dir: PathBuf,
+    virtual_root: PathBuf,
+    state: SyncState,
+}
+
+impl<'a, T: AsyncCloudBackend> FolderSync<'a, T> {
+
+    pub fn new(cloud: &'a Cloud<T>, local_dir: &Path, virtual_root: &Path) -> Self {
+        Self {
+            cloud,
+            local_dir: local_dir.to_path_buf(),
+            virtual_root: virtual_root.to_path_buf(),
+            state: SyncState::load(local_dir, virtual_root),
+        }
+    }
+
+    /// План синхронизации без изменений на диске и в облаке.
+    ///
+    /// Изменения находятся сравнением с состоянием после прошлой синхронизации: локальный
+    /// файл изменен, если изменилось его содержимое, файл VFS - если у него другой метафайл.
+    /// Изменение побеждает удаление, изменения с обеих сторон дают конфликт. Файлы,
+    /// которые отличаются только расширением и попадают в один файл VFS, тоже дают конфликт.
+    /// Если действие над файлом в прошлый раз не завершилось, пропавший локальный файл
+    /// скачивается заново, а не удаляется из облака.
+    pub fn plan(&self) -> Result<SyncPlan, CloudError> {
+
+        let local_files = self.scan_local()?;
+        let remote_files = self.scan_remote()?;
+
+        let paths = local_files
+            .keys()
+            .chain(remote_files.keys())
+            .chain(self.state.files.keys())
+            .cloned()
+            .collect::<BTreeSet<_>>();
+
+        let mut plan = SyncPlan::default();
+
+        let mut virtual_paths: BTreeMap<PathBuf, BTreeSet<String>> = BTreeMap::new();
+        for path in local_files.keys().chain(remote_files.keys()) {
+            virtual_paths.entry(self.virtual_path(path)).or_default().insert(path.clone());
+        }
+
+        let mut colliding = BTreeSet::new();
+        for paths in virtual_paths.into_values().filter(|paths| paths.len() > 1) {
+            colliding.extend(paths.iter().cloned());
+            plan.actions.push(SyncAction::NameCollision(paths.into_iter().collect()));
+        }
+
+        for path in paths.into_iter().filter(|path| !colliding.contains(path)) {
+            let synced = self.state.files.get(&path);
+            let local = local_files.get(&path);
+            let remote = remote_files.get(&path);
+
+            let local_changed = match (local, synced) {
+                (Some(local), Some(synced)) => local.md5 != synced.md5,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            let remote_changed = match (remote, synced) {
+                (Some(remote), Some(synced)) => remote.build_metafile != synced.build_metafile,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+
+            let action = match (local, remote) {
+                (Some(_), Some(_)) if local_changed && remote_changed => Some(SyncAction::Conflict {
+                    copy: self.conflict_copy_name(&path, &local_files, &remote_files),
+                    path,
+                }),
+                (Some(_), _) if local_changed => Some(SyncAction::Upload(path)),
+                (_, Some(_)) if remote_changed => Some(SyncAction::Download(path)),
+                (Some(_), None) => Some(SyncAction::DeleteLocal(path)),
+                (None, Some(_)) if self.state.unfinished.contains(&path) => Some(SyncAction::Download(path)),
+                (None, Some(_)) => Some(SyncAction::DeleteRemote(path)),
+                (None, None) => Some(SyncAction::Forget(path)),
+                (Some(_), Some(_)) => None,
+            };
+
+            plan.actions.extend(action);
+        }
+
+        Ok(plan)
+    }
+
+    /// Выполнение плана, ошибка одного действия не прерывает остальные
+    pub async fn apply(&mut self, plan: &SyncPlan) -> Result<SyncReport, CloudError> {
+
+        let mut report = SyncReport::default();
+
+        for action in &plan.actions {
+            // Отметка снимается только после успешного действия, в том числе если процесс прервется
+            if let Some(path) = action.path() {
+                self.state.unfinished.insert(path.to_owned());
+                self.state.save(&self.local_dir)?;
+            }
+
+            match self.apply_action(action).await {
+                Ok(()) => {
+                    if let Some(path) = action.path() {
+                        self.state.unfinished.remove(path);
+                    }
+                    report.applied.push(action.clone())
+                },
+                Err(e) => report.failed.push((action.clone(), format!("{:?}", e))),
+            }
+
+            self.state.save(&self.local_dir)?;
+        }
+
+        Ok(report)
+    }
+
+    async fn apply_action(&mut self, action: &SyncAction) -> Result<(), CloudError> {
+        match action {
+            SyncAction::Upload(path) => self.upload(path).await,
+            SyncAction::Download(path) => self.download(path).await,
+
+            SyncAction::DeleteLocal(path) => {
+                fs::remove_file(self.local_dir.join(path))?;
+                self.state.files.remove(path);
+                Ok(())
+            },
+
+            SyncAction::DeleteRemote(path) => {
+                self.cloud.remove_file(&self.virtual_path(path))?;
+                self.state.files.remove(path);
+                Ok(())
+            },
+
+            SyncAction::Conflict { path, copy } => {
+                fs::rename(self.local_dir.join(path), self.local_dir.join(copy))?;
+                self.upload(copy).await?;
+                self.download(path).await
+            },
+
+            SyncAction::Forget(path) => {
+                self.state.files.remove(path);
+                Ok(())
+            },
+
+            SyncAction::NameCollision(paths) =>
+                Err(CloudError::NameCollision(paths.iter().map(|path| self.local_dir.join(path)).collect()))
+        }
+    }
+
+    async fn upload(&mut self, path: &str) -> Result<(), CloudError> {
+        let local_path = self.local_dir.join(path);
+        let virtual_path = self.virtual_path(path);
+        let virtual_folder = virtual_path.parent().unwrap();
+
+        // Новая версия заменяет старую только после загрузки
+        self.cloud.create_folder(virtual_folder)?;
+        self.cloud.async_replace_file(&local_path, virtual_folder).await?;
+
+        self.remember(path)
+    }
+
+    async fn download(&mut self, path: &str) -> Result<(), CloudError> {
+        let file = self.cloud.get_file(&self.virtual_path(path))?;
+        let local_path = self.local_dir.join(path);
+
+        self.cloud
+            .async_download_file(&self.virtual_path(path), &local_path, ConflictPolicy::Overwrite)
+            .await?;
+        folder_download::restore_metadata(&local_path, &file.metadata)?;
+
+        self.remember(path)
+    }
+
+    /// Запись текущего состояния файла как синхронизированного
+    fn remember(&mut self, path: &str) -> Result<(), CloudError> {
+        let local_file = read_local_file(&self.local_dir.join(path))?;
+        let remote_file = self.cloud.get_file(&self.virtual_path(path))?;
+
+        self.state.files.insert(path.to_owned(), SyncedFile {
+            size: local_file.size,
+            modified: local_file.modified,
+            md5: local_file.md5,
+            build_metafile: remote_file.build_metafile,
+        });
+
+        Ok(())
+    }
+
+    /// Путь файла в VFS: файлы VFS называются без расширения
+    fn virtual_path(&self, path: &str) -> PathBuf {
+        let path = Path::new(path);
+        let name = path.file_stem().unwrap_or_default();
+
+        match path.parent() {
+            Some(parent) => self.virtual_root.join(parent).join(name),
+            None => self.virtual_root.join(name),
+        }
+    }
+
+    fn conflict_copy_name(
+        &self,
+        path: &str,
+        local_files: &BTreeMap<String, LocalFile>,
+        remote_files: &BTreeMap<String, VFSFile>
+    ) -> String {
+        let path = Path::new(path);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let extension = path.extension().map(|extension| format!(".{}", extension.to_string_lossy()));
+
+        (1..)
+            .map(|index| {
+                let suffix = if index == 1 { String::new() } else { format!(" {}", index) };
+                let name = format!("{} (conflict{}){}", stem, suffix, extension.as_deref().unwrap_or(""));
+                path.with_file_name(name).to_string_lossy().to_string()
+            })
+            .find(|candidate| !local_files.contains_key(candidate) && !remote_files.contains_key(candidate))
+            .unwrap()
+    }
+
+    /// Локальные файлы по относительному пути. Хеш пересчитывается только для файлов,
+    /// у которых изменились размер или время изменения
+    fn scan_local(&self) -> Result<BTreeMap<String, LocalFile>, CloudError> {
+
+        let mut files = BTreeMap::new();
+        let mut dirs = vec![self.local_dir.clone()];
+
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                let metadata = fs::symlink_metadata(&path)?;
+
+                if metadata.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+
+                if !metadata.is_file() {
+                    continue;
+                }
+
+                let relative_path = path
+                    .strip_prefix(&self.local_dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string();
+
+                if relative_path.starts_with(SYNC_STATE_FILE) {
+                    continue;
+                }
+
+                let modified = modified_secs(&metadata);
+
+                let local_file = match self.state.files.get(&relative_path) {
+                    Some(synced) if synced.size == metadata.len() && synced.modified == modified => LocalFile {
+                        size: synced.size,
+                        modified,
+                        md5: synced.md5.clone(),
+                    },
+                    _ => read_local_file(&path)?,
+                };
+
+                files.insert(relative_path, local_file);
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Файлы папки VFS по относительному локальному пути
+    fn scan_remote(&self) -> Result<BTreeMap<String, VFSFile>, CloudError> {
+
+        fn walk(folder: &VFSFolder, relative_path: &Path, output: &mut BTreeMap<String, VFSFile>) {
+            for node in folder.children.values() {
+                match node {
+                    FileSystemNode::File(file) if file.metadata.symlink.is_none() => {
+                        let path = relative_path.join(folder_download::local_file_name(file));
+                        output.insert(path.to_string_lossy().to_string(), file.clone());
+                    },
+                    FileSystemNode::File(_) => {},
+                    FileSystemNode::Folder(child_folder) =>
+                        walk(child_folder, &relative_path.join(&child_folder.name), output),
+                }
+            }
+        }
+
+        let mut files = BTreeMap::new();
+
+        match self.cloud.get_folder(&self.virtual_root) {
+            Ok(folder) => walk(&folder, Path::new(""), &mut files),
+            // Папка еще не создана, все локальные файлы будут загружены
+            Err(CloudError::VFSError(_)) => {},
+            Err(e) => return Err(e),
+        }
+
+        Ok(files)
+    }
+}
+
+fn read_local_file(path: &Path) -> io::Result<LocalFile> {
+    let checksum = Checksum::of_file(path)?;
+
+    Ok(LocalFile {
+        size: checksum.size,
+        modified: modified_secs(&fs::metadata(path)?),
+        md5: checksum.md5,
+    })
+}
+
+impl Display for SyncAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncAction::Upload(path) => write!(f, "> {}", path),
+            SyncAction::Download(path) => write!(f, "< {}", path),
+            SyncAction::DeleteLocal(path) => write!(f, "- {} (локально)", path),
+            SyncAction::DeleteRemote(path) => write!(f, "- {} (в облаке)", path),
+            SyncAction::Conflict { path, copy } =>
+                write!(f, "! {} изменен с обеих сторон, локальная версия: {}", path, copy),
+            SyncAction::Forget(path) => write!(f, "  {} удален с обеих сторон", path),
+            SyncAction::NameCollision(paths) =>
+                write!(f, "! {} отличаются только расширением, не синхронизируются", paths.join(", ")),
+        }
+    }
+}
+
+impl Display for SyncPlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.actions.is_empty() {
+            return writeln!(f, "Папки синхронизированы");
+        }
+
+        for action in &self.actions {
+            writeln!(f, "{}", action)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for SyncReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Выполнено действий: {}", self.applied.len())?;
+
+        writeln!(f, "Ошибок: {}", self.failed.len())?;
+        for (action, error) in &self.failed {
+            writeln!(f, "  {} - {}", action, error)?;
+        }
+
+        Ok(())
+    }
+}
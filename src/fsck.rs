@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use telegram_drive_file::file_assembly;
+
+use crate::cloud::{Cloud, CloudError};
+use crate::cloud_backend::{AsyncCloudBackend, RemoteFile};
+use crate::virtual_file_system::{Checksum, FileSystemNode, VFSFile};
+
+/// Расширение, с которым локальная копия откладывается на время проверки
+const REPLICA_EXTENSION: &str = "fsck-replica";
+
+#[derive(Debug, Clone, Default)]
+pub struct FsckOptions {
+    /// Скачивать метафайлы и части и сверять их содержимое
+    pub deep: bool,
+    /// Перезаливать поврежденные файлы из локальных копий
+    pub repair: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProblemKind {
+    /// Файла нет в облаке
+    Missing,
+    SizeMismatch { expected: u64, actual: u64 },
+    ChecksumMismatch,
+    /// Заголовок части не совпадает с хешем из метафайла
+    BadPartHeader,
+    /// Метафайл описывает другие части, чем VFS
+    MetafileMismatch,
+    Unreadable(String),
+}
+
+/// Проблема с файлом `remote_name`, принадлежащим `path` в VFS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsckProblem {
+    pub path: PathBuf,
+    pub remote_name: String,
+    pub kind: ProblemKind,
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// Количество проверенных файлов VFS
+    pub checked: usize,
+    pub problems: Vec<FsckProblem>,
+    /// Файлы, перезалитые из локальных копий
+    pub repaired: Vec<String>,
+    /// Поврежденные файлы, для которых нет пригодной копии
+    pub unrepairable: Vec<String>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Файлы VFS, которые нельзя собрать из облака
+    pub fn broken_files(&self) -> Vec<&Path> {
+        let mut paths = self.problems
+            .iter()
+            .map(|problem| problem.path.as_path())
+            .collect::<Vec<_>>();
+        paths.dedup();
+        paths
+    }
+}
+
+/// Проверка, что каждый файл VFS можно восстановить из облака.
+///
+/// Для каждого `VFSFile` проверяется наличие метафайла и всех частей, а также их
+/// размер, если при загрузке были записаны контрольные суммы. В глубоком режиме файлы
+/// скачиваются и сверяются по содержимому. Единственный источник для починки - копии
+/// в рабочей папке, совпадающие с записанной контрольной суммой.
+pub async fn check<T: AsyncCloudBackend>(cloud: &Cloud<T>, options: &FsckOptions) -> Result<FsckReport, CloudError> {
+
+    let remote_files = cloud
+        .backend()
+        .list_files()
+        .await?
+        .into_iter()
+        .map(|file| (file.name.clone(), file))
+        .collect::<HashMap<_, _>>();
+
+    let mut report = FsckReport::default();
+
+    for (path, node) in cloud.get_vfs().nodes() {
+        let FileSystemNode::File(file) = node else {
+            continue;
+        };
+
+        // Ссылки хранят только путь цели, в облаке для них ничего нет
+        if file.metadata.symlink.is_some() {
+            continue;
+        }
+
+        report.checked += 1;
+
+        for remote_name in file.parts_name.iter().chain([&file.build_metafile]) {
+            let kind = match check_remote_file(cloud, file, remote_name, remote_files.get(remote_name), options).await {
+                None => continue,
+                Some(kind) => kind,
+            };
+
+            report.problems.push(FsckProblem {
+                path: path.clone(),
+                remote_name: remote_name.clone(),
+                kind,
+            });
+
+            if !options.repair {
+                continue;
+            }
+
+            if repair(cloud, file, remote_name, remote_files.contains_key(remote_name)).await? {
+                report.repaired.push(remote_name.clone());
+            } else {
+                report.unrepairable.push(remote_name.clone());
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+async fn check_remote_file<T: AsyncCloudBackend>(
+    cloud: &Cloud<T>,
+    file: &VFSFile,
+    remote_name: &str,
+    remote_file: Option<&RemoteFile>,
+    options: &FsckOptions
+) -> Option<ProblemKind> {
+
+    let Some(remote_file) = remote_file else {
+        return Some(ProblemKind::Missing);
+    };
+    let checksum = file.metadata.checksums.get(remote_name);
+
+    if let Some(checksum) = checksum {
+        if checksum.size != remote_file.size {
+            return Some(ProblemKind::SizeMismatch { expected: checksum.size, actual: remote_file.size });
+        }
+    }
+
+    if !options.deep {
+        return None;
+    }
+
+    let local_path = cloud.work_dir().join(remote_name);
+    let replica_path = local_path.with_extension(REPLICA_EXTENSION);
+
+    // Скачанный файл не должен затереть локальную копию, из которой можно починить облако
+    let has_replica = local_path.is_file() && fs::rename(&local_path, &replica_path).is_ok();
+
+    let problem = match cloud.backend().download_file(&local_path).await {
+        Ok(()) => verify_content(file, remote_name, &local_path, checksum),
+        Err(e) => Some(ProblemKind::Unreadable(format!("{:?}", e))),
+    };
+
+    if has_replica {
+        let _ = fs::rename(&replica_path, &local_path);
+    } else {
+        let _ = fs::remove_file(&local_path);
+    }
+
+    problem
+}
+
+/// Проверка содержимого файла, лежащего по `local_path`
+fn verify_content(file: &VFSFile, remote_name: &str, local_path: &Path, checksum: Option<&Checksum>) -> Option<ProblemKind> {
+
+    if let Some(checksum) = checksum {
+        match Checksum::of_file(local_path) {
+            Ok(actual) if &actual == checksum => {},
+            Ok(_) => return Some(ProblemKind::ChecksumMismatch),
+            Err(e) => return Some(ProblemKind::Unreadable(e.to_string())),
+        }
+    }
+
+    if remote_name == file.build_metafile {
+        return match file_assembly::read_metafile(local_path) {
+            Ok(metafile) if metafile.parts_name() == file.parts_name => None,
+            Ok(_) => Some(ProblemKind::MetafileMismatch),
+            Err(e) => Some(ProblemKind::Unreadable(format!("{:?}", e))),
+        };
+    }
+
+    // Часть начинается с md5 своего имени, который записан и в метафайл
+    let mut header = [0_u8; 16];
+    match fs::File::open(local_path).and_then(|mut part| part.read_exact(&mut header)) {
+        Ok(()) if header == md5::compute(remote_name).0 => None,
+        Ok(()) => Some(ProblemKind::BadPartHeader),
+        Err(e) => Some(ProblemKind::Unreadable(e.to_string())),
+    }
+}
+
+/// Перезаливка файла из локальной копии, `false` - пригодной копии нет
+async fn repair<T: AsyncCloudBackend>(
+    cloud: &Cloud<T>,
+    file: &VFSFile,
+    remote_name: &str,
+    exists_remotely: bool
+) -> Result<bool, CloudError> {
+
+    let local_path = cloud.work_dir().join(remote_name);
+
+    if !local_path.is_file()
+        || verify_content(file, remote_name, &local_path, file.metadata.checksums.get(remote_name)).is_some()
+    {
+        return Ok(false);
+    }
+
+    if exists_remotely {
+        cloud.backend().remove_file(&local_path).await?;
+    }
+    cloud.backend().upload_file(&local_path).await?;
+
+    Ok(true)
+}
+
+impl Display for ProblemKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ProblemKind::Missing => write!(f, "нет в облаке"),
+            ProblemKind::SizeMismatch { expected, actual } =>
+                write!(f, "размер {} байт вместо {}", actual, expected),
+            ProblemKind::ChecksumMismatch => write!(f, "контрольная сумма не совпадает"),
+            ProblemKind::BadPartHeader => write!(f, "заголовок части поврежден"),
+            ProblemKind::MetafileMismatch => write!(f, "метафайл описывает другие части"),
+            ProblemKind::Unreadable(error) => write!(f, "не читается: {}", error),
+        }
+    }
+}
+
+impl Display for FsckReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Проверено файлов: {}", self.checked)?;
+        writeln!(f, "Поврежденных файлов: {}", self.broken_files().len())?;
+
+        for problem in &self.problems {
+            writeln!(f, "  ! {} - {}: {}", problem.path.display(), problem.remote_name, problem.kind)?;
+        }
+
+        if !self.repaired.is_empty() {
+            writeln!(f, "Восстановлено из локальных копий: {}", self.repaired.len())?;
+        }
+
+        for remote_name in &self.unrepairable {
+            writeln!(f, "  ? {} - нет пригодной копии", remote_name)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, SystemTime};
+
+use crate::cloud::{Cloud, CloudError};
+use crate::cloud_backend::{AsyncCloudBackend, RemoteFile};
+use crate::virtual_file_system::{FileSystemNode, VirtualFileSystem};
+use crate::vfs_index;
+
+#[derive(Debug, Clone)]
+pub struct GCOptions {
+    /// Только отчет, без удаления
+    pub dry_run: bool,
+    /// Файлы моложе этого срока не удаляются: они могут принадлежать незавершенной загрузке
+    pub grace_period: Duration,
+}
+
+impl Default for GCOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: true,
+            grace_period: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GCReport {
+    /// Файлы в облаке, на которые не ссылается ни одна VFS
+    pub unreferenced: Vec<RemoteFile>,
+    /// Неиспользуемые файлы, пропущенные из-за срока ожидания
+    pub in_grace_period: Vec<RemoteFile>,
+    pub deleted: Vec<String>,
+    pub failed: Vec<(String, String)>,
+}
+
+impl GCReport {
+    pub fn unreferenced_size(&self) -> u64 {
+        self.unreferenced.iter().map(|file| file.size).sum()
+    }
+}
+
+/// Сборка мусора в облаке.
+///
+/// Файл считается используемым, если на него как на часть или метафайл ссылается
+/// текущая VFS, любой снимок VFS, хранящийся в облаке, или незавершенная загрузка.
+/// Из нескольких загруженных копий файла с одним именем используется последняя,
+/// остальные считаются мусором.
+/// Сами снимки VFS не удаляются.
+pub async fn collect_garbage<T: AsyncCloudBackend>(
+    cloud: &Cloud<T>,
+    options: &GCOptions
+) -> Result<GCReport, CloudError> {
+
+    let mut referenced = HashSet::new();
+    collect_references(&cloud.get_vfs(), &mut referenced);
+
+    // Части прерванных загрузок еще понадобятся при их продолжении
+    for session in cloud.upload_sessions() {
+        referenced.extend(session.files().map(|part| part.name.clone()));
+    }
+
+    // Снимки других устройств могут ссылаться на файлы, которых еще нет в локальной VFS
+    if cloud.has_index_passphrase() {
+        for snapshot in cloud.remote_index_snapshots().await? {
+            collect_references(&snapshot, &mut referenced);
+        }
+    }
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let remote_files = cloud.backend().list_files().await?;
+
+    // Последняя копия каждого файла
+    let mut latest_copies: HashMap<&str, i64> = HashMap::new();
+    for remote_file in &remote_files {
+        let latest_id = latest_copies.entry(&remote_file.name).or_insert(remote_file.id);
+        *latest_id = (*latest_id).max(remote_file.id);
+    }
+
+    let mut report = GCReport::default();
+
+    for remote_file in remote_files.iter().cloned() {
+        let is_latest_copy = latest_copies[remote_file.name.as_str()] == remote_file.id;
+
+        if is_latest_copy && (referenced.contains(&remote_file.name)
+            || vfs_index::parse_index_version(&remote_file.name).is_some())
+        {
+            continue;
+        }
+
+        if now - remote_file.date < options.grace_period.as_secs() as i64 {
+            report.in_grace_period.push(remote_file);
+        } else {
+            report.unreferenced.push(remote_file);
+        }
+    }
+
+    report.unreferenced.sort_by(|a, b| a.name.cmp(&b.name));
+
+    if options.dry_run {
+        return Ok(report);
+    }
+
+    for remote_file in &report.unreferenced {
+        match cloud.backend().remove_remote_file(remote_file).await {
+            Ok(()) => report.deleted.push(remote_file.name.clone()),
+            Err(e) => report.failed.push((remote_file.name.clone(), format!("{:?}", e))),
+        }
+    }
+
+    Ok(report)
+}
+
+fn collect_references(vfs: &VirtualFileSystem, referenced: &mut HashSet<String>) {
+    for (_, node) in vfs.nodes() {
+        if let FileSystemNode::File(file) = node {
+            referenced.insert(file.build_metafile.clone());
+            referenced.extend(file.parts_name.iter().cloned());
+        }
+    }
+}
+
+impl Display for GCReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Неиспользуемых файлов: {} ({} байт)",
+            self.unreferenced.len(),
+            self.unreferenced_size()
+        )?;
+        for remote_file in &self.unreferenced {
+            writeln!(f, "  - {} ({} байт)", remote_file.name, remote_file.size)?;
+        }
+
+        if !self.in_grace_period.is_empty() {
+            writeln!(f, "Пропущено недавно загруженных: {}", self.in_grace_period.len())?;
+        }
+
+        if !self.deleted.is_empty() {
+            writeln!(f, "Удалено: {}", self.deleted.len())?;
+        }
+
+        for (name, error) in &self.failed {
+            writeln!(f, "  ! {} не удален: {}", name, error)?;
+        }
+
+        Ok(())
+    }
+}
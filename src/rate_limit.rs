@@ -0,0 +1,121 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::NaiveTime;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+/// Файл расписания скорости в папке данных
+const SCHEDULE_FILE: &str = "bandwidth.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+    Upload,
+    Download,
+}
+
+/// Ограничение скорости в байтах в секунду, `None` - без ограничения
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download: Option<u64>,
+}
+
+impl RateLimit {
+    pub fn for_direction(&self, direction: TransferDirection) -> Option<u64> {
+        match direction {
+            TransferDirection::Upload => self.upload,
+            TransferDirection::Download => self.download,
+        }
+    }
+}
+
+/// Ограничение, действующее с `start` до `end` по местному времени.
+/// Если `end` раньше `start`, период переходит через полночь
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduleRule {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub limit: RateLimit,
+}
+
+impl ScheduleRule {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Общие ограничения скорости всех передач облака
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthSchedule {
+    /// Ограничение вне периодов `rules`
+    #[serde(default)]
+    pub default: RateLimit,
+    /// Периоды с другим ограничением, действует первый подходящий
+    #[serde(default)]
+    pub rules: Vec<ScheduleRule>,
+}
+
+impl BandwidthSchedule {
+
+    pub fn limit_at(&self, time: NaiveTime) -> RateLimit {
+        self.rules
+            .iter()
+            .find(|rule| rule.contains(time))
+            .map_or(self.default, |rule| rule.limit)
+    }
+
+    /// Расписание из папки данных, без файла расписания скорость не ограничена
+    pub fn load(data_dir: &Path) -> io::Result<Self> {
+        match fs::read(data_dir.join(SCHEDULE_FILE)) {
+            Ok(schedule) => Ok(serde_json::from_slice(&schedule)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, data_dir: &Path) -> io::Result<()> {
+        fs::write(data_dir.join(SCHEDULE_FILE), serde_json::to_vec_pretty(self)?)
+    }
+}
+
+/// Равномерное распределение передаваемых байт во времени.
+///
+/// Передачи резервируют свой объем заранее: каждая следующая начинается не раньше,
+/// чем при заданной скорости закончилась бы предыдущая.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    next_free: Mutex<Instant>,
+}
+
+impl Throttle {
+
+    pub fn new() -> Self {
+        Self { next_free: Mutex::new(Instant::now()) }
+    }
+
+    /// Ожидание возможности передать `bytes` байт со скоростью `rate`
+    pub async fn acquire(&self, bytes: u64, rate: Option<u64>) {
+        let Some(rate) = rate.filter(|rate| *rate > 0) else {
+            return;
+        };
+
+        let start = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let start = (*next_free).max(Instant::now());
+            *next_free = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+            start
+        };
+
+        tokio::time::sleep_until(start).await;
+    }
+}
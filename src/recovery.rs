@@ -0,0 +1,162 @@
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+
+use telegram_drive_file::file_assembly;
+
+use crate::cloud::{Cloud, CloudError};
+use crate::cloud_backend::AsyncCloudBackend;
+use crate::virtual_file_system::{FileSystemNode, VFSFile};
+
+/// Папка для файлов, исходное расположение которых неизвестно
+pub const RECOVERED_FOLDER: &str = "fs://recovered";
+
+/// Результат восстановления VFS по файлам в облаке
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    /// Файлы, добавленные в VFS
+    pub recovered: Vec<PathBuf>,
+    /// Метафайлы, уже описанные в VFS
+    pub already_known: Vec<String>,
+    /// Метафайлы, для которых в облаке не хватает частей (метафайл, отсутствующие части)
+    pub incomplete: Vec<(String, Vec<String>)>,
+    /// Метафайлы, которые не удалось скачать или прочитать
+    pub unreadable: Vec<(String, String)>,
+    /// Части, для которых нет ни одного метафайла
+    pub orphan_parts: Vec<String>,
+}
+
+/// Восстановление VFS по метафайлам, хранящимся в облаке.
+///
+/// Каждый метафайл скачивается и декодируется. Файлы, которых нет в текущей VFS,
+/// добавляются в `fs://recovered/`, так как метафайл не хранит исходную папку.
+pub async fn recover_vfs<T: AsyncCloudBackend>(cloud: &Cloud<T>) -> Result<RecoveryReport, CloudError> {
+
+    let mut report = RecoveryReport::default();
+
+    let remote_files = cloud.backend().list_files().await?;
+    let remote_names = remote_files
+        .iter()
+        .map(|file| file.name.clone())
+        .collect::<HashSet<_>>();
+
+    let known_metafiles = cloud
+        .get_vfs()
+        .nodes()
+        .into_iter()
+        .filter_map(|(_, node)| match node {
+            FileSystemNode::File(file) => Some(file.build_metafile.clone()),
+            FileSystemNode::Folder(_) => None,
+        })
+        .collect::<HashSet<_>>();
+
+    let mut metafile_names = remote_names
+        .iter()
+        .filter(|name| name.ends_with(".meta"))
+        .cloned()
+        .collect::<Vec<_>>();
+    metafile_names.sort();
+
+    let mut referenced_parts = HashSet::new();
+
+    for metafile_name in metafile_names {
+
+        if let Err(e) = cloud.backend().download_file(Path::new(&metafile_name)).await {
+            report.unreadable.push((metafile_name, format!("{:?}", e)));
+            continue;
+        }
+
+        let metafile = match file_assembly::read_metafile(&cloud.work_dir().join(&metafile_name)) {
+            Ok(metafile) => metafile,
+            Err(e) => {
+                report.unreadable.push((metafile_name, format!("{:?}", e)));
+                continue;
+            }
+        };
+
+        let parts_name = metafile.parts_name();
+        referenced_parts.extend(parts_name.iter().cloned());
+
+        let missing_parts = parts_name
+            .iter()
+            .filter(|part| !remote_names.contains(*part))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if !missing_parts.is_empty() {
+            report.incomplete.push((metafile_name.clone(), missing_parts));
+        }
+
+        if known_metafiles.contains(&metafile_name) {
+            report.already_known.push(metafile_name);
+            continue;
+        }
+
+        cloud.create_folder(Path::new(RECOVERED_FOLDER))?;
+
+        let name = free_name(cloud, &metafile.source_filename);
+
+        cloud.add_file(Path::new(RECOVERED_FOLDER), VFSFile {
+            name: name.clone(),
+            extension: metafile.source_format.clone(),
+            build_metafile: metafile_name,
+            parts_name,
+            metadata: Default::default(),
+        })?;
+
+        report.recovered.push(Path::new(RECOVERED_FOLDER).join(name));
+    }
+
+    report.orphan_parts = remote_names
+        .into_iter()
+        .filter(|name| name.ends_with(".part") && !referenced_parts.contains(name))
+        .collect();
+    report.orphan_parts.sort();
+
+    Ok(report)
+}
+
+/// Имя, не занятое в папке восстановленных файлов
+fn free_name<T: AsyncCloudBackend>(cloud: &Cloud<T>, name: &str) -> String {
+    let is_free = |candidate: &str| cloud
+        .get_folder(Path::new(RECOVERED_FOLDER))
+        .map(|folder| !folder.children.contains_key(candidate))
+        .unwrap_or(true);
+
+    if is_free(name) {
+        return name.to_owned();
+    }
+
+    (2..)
+        .map(|index| format!("{} ({})", name, index))
+        .find(|candidate| is_free(candidate))
+        .unwrap()
+}
+
+impl Display for RecoveryReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Восстановлено файлов: {}", self.recovered.len())?;
+        for path in &self.recovered {
+            writeln!(f, "  + {}", path.display())?;
+        }
+
+        writeln!(f, "Уже есть в VFS: {}", self.already_known.len())?;
+
+        writeln!(f, "Неполные файлы: {}", self.incomplete.len())?;
+        for (metafile, missing_parts) in &self.incomplete {
+            writeln!(f, "  ! {} - нет частей: {}", metafile, missing_parts.join(", "))?;
+        }
+
+        writeln!(f, "Нечитаемые метафайлы: {}", self.unreadable.len())?;
+        for (metafile, error) in &self.unreadable {
+            writeln!(f, "  ! {} - {}", metafile, error)?;
+        }
+
+        writeln!(f, "Части без метафайла: {}", self.orphan_parts.len())?;
+        for part in &self.orphan_parts {
+            writeln!(f, "  ? {}", part)?;
+        }
+
+        Ok(())
+    }
+}
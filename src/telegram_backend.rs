@@ -12,18 +12,22 @@ use std::io::{Read, Write};
 use std::path::Path;
 use std::sync::atomic::AtomicI64;
 use tokio::sync::RwLock as AsyncRwLock;
+use tokio_util::sync::CancellationToken;
 use async_trait::async_trait;
 use serde::de::Unexpected::Str;
 
 use serde_json::{json, Value};
 use telegram_drive_core::{self, TDApp};
+use telegram_drive_core::api::{Chat, Message};
+use telegram_drive_core::retry::RetryPolicy;
 use telegram_drive_file::file_separation;
 
 use crate::cloud::CloudError;
-use crate::cloud_backend::AsyncCloudBackend;
+use crate::cloud_backend::{AsyncCloudBackend, RemoteFile};
 use crate::virtual_file_system::VFSFile;
 
 const CLOUD_CHAT_ID: i64 = -1001976761155;
+const CLOUD_CHAT_TITLE: &str = "TelegramDrive";
 
 type CallbackEvent = Box<dyn FnOnce(&TDApp) -> bool + Send + 'static>;
 
@@ -31,8 +35,12 @@ type CallbackEvent = Box<dyn FnOnce(&TDApp) -> bool + Send + 'static>;
 pub struct TelegramBackend {
     cloud_chat_id: i64,
     telegram: TDApp,
-    cloud_chat: Value,
-    files: AsyncRwLock<HashMap<String, (i64, Value)>>,
+    cloud_chat: Chat,
+    /// Сообщения с файлами облака по имени файла, от старых к новым.
+    /// Повторная загрузка файла с тем же именем добавляет еще одно сообщение
+    files: AsyncRwLock<HashMap<String, Vec<Message>>>,
+    /// Повтор запросов при FLOOD_WAIT, сетевых ошибках и ошибках сервера
+    retry: RetryPolicy,
 }
 
 impl TelegramBackend {
@@ -67,25 +75,49 @@ impl TelegramBackend {
     }
      */
 
-    fn get_files_from_message(messages: Vec<Value>) -> HashMap<String, (i64, Value)> {
-
-        let mut files_hm = HashMap::with_capacity(messages.len());
+    fn get_files_from_message(mut messages: Vec<Message>) -> HashMap<String, Vec<Message>> {
+        messages.sort_by_key(|message| message.id);
 
+        let mut files: HashMap<String, Vec<Message>> = HashMap::new();
         for message in messages {
-            let message_id = message["id"].as_i64().unwrap();
+            if let Some(document) = message.document() {
+                files.entry(document.file_name.clone()).or_default().push(message);
+            }
+        }
 
-            if !message["content"]["document"].is_null() {
+        files
+    }
 
-                let document = &message["content"]["document"];
+    /// Последнее загруженное сообщение с файлом
+    async fn latest_message(&self, file_name: &str) -> Result<Message, CloudError> {
+        self.files
+            .read()
+            .await
+            .get(file_name)
+            .and_then(|messages| messages.last())
+            .cloned()
+            .ok_or(CloudError::RemoteFileNotFound(file_name.to_owned()))
+    }
 
-                let file_name = document["file_name"].as_str().unwrap().to_owned();
-                let file_id = document["document"]["id"].as_i64().unwrap();
+    fn remote_file_from_message(message: &Message) -> RemoteFile {
+        let document = message.document();
 
-                files_hm.insert(file_name.to_owned(), (file_id, message));
-            }
+        RemoteFile {
+            id: message.id,
+            name: document.map(|document| document.file_name.clone()).unwrap_or_default(),
+            size: document.map(|document| document.document.size).unwrap_or_default(),
+            date: message.date,
         }
+    }
 
-        files_hm
+    /// Поиск облачного чата, созданного на другом устройстве
+    fn find_cloud_chat(app: &TDApp) -> Option<Chat> {
+        let chat_ids = futures::executor::block_on(app.search_chats(CLOUD_CHAT_TITLE));
+
+        chat_ids
+            .into_iter()
+            .filter_map(|chat_id| futures::executor::block_on(app.get_chat(chat_id)).ok())
+            .find(|chat| chat.title == CLOUD_CHAT_TITLE)
     }
 
     async fn build_file() -> Result<PathBuf, CloudError> {
@@ -105,7 +137,6 @@ impl AsyncCloudBackend for TelegramBackend {
         // Создание телеграм клиента
         let mut app = futures::executor::block_on(TDApp::create());
         futures::executor::block_on(app.account_auth()).expect("Ошибка авторизации в Telegram");
-        futures::executor::block_on(app.skip_all_update(0.1));
 
         let cloud_chat_id: i64;
 
@@ -116,13 +147,16 @@ impl AsyncCloudBackend for TelegramBackend {
             let json_file = serde_json::from_str::<Value>(&file_str)
                 .expect("telegram_backend.json содержит невалидный json");
 
-            cloud_chat_id = json_file["cloud_chat_id"].as_i64().unwrap();
+            cloud_chat_id = json_file["cloud_chat_id"].as_i64().expect("В telegram_backend.json нет cloud_chat_id");
 
         } else {
 
-            let new_cloud_chat = futures::executor::block_on(app.create_chat());
+            let new_cloud_chat = match TelegramBackend::find_cloud_chat(&app) {
+                Some(chat) => chat,
+                None => futures::executor::block_on(app.create_chat()).expect("Не удалось создать облачный чат")
+            };
 
-            cloud_chat_id = new_cloud_chat["id"].as_i64().unwrap();
+            cloud_chat_id = new_cloud_chat.id;
 
             let json_file = json!({
                 "cloud_chat_id": cloud_chat_id,
@@ -132,7 +166,7 @@ impl AsyncCloudBackend for TelegramBackend {
             fs::write("telegram_backend.json", json_file.as_bytes()).unwrap();
         }
 
-        let cloud_chat = dbg!(futures::executor::block_on(app.get_chat(cloud_chat_id)));
+        let cloud_chat = futures::executor::block_on(app.get_chat(cloud_chat_id)).expect("Облачный чат недоступен");
 
         let messages = dbg!(futures::executor::block_on(app.load_all_messages(cloud_chat_id)));
 
@@ -144,6 +178,7 @@ impl AsyncCloudBackend for TelegramBackend {
             telegram: app,
             cloud_chat,
             files: AsyncRwLock::new(TelegramBackend::get_files_from_message(messages)),
+            retry: RetryPolicy::default(),
         }
     }
     async fn load_backend(&self) -> Result<(), CloudError> {
@@ -155,41 +190,127 @@ impl AsyncCloudBackend for TelegramBackend {
         Ok(())
     }
 
-    async fn upload_file(&self, file_path: &Path) -> Result<(), CloudError> {
-        let result_upload = self.telegram.upload_file(file_path, self.cloud_chat_id).await;
+    async fn upload_file(&self, file_path: &Path) -> Result<RemoteFile, CloudError> {
+        self.upload_file_with_progress(file_path, &|_, _| {}, &CancellationToken::new()).await
+    }
+
+    async fn download_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        self.download_file_with_progress(file_path, &|_, _| {}, &CancellationToken::new()).await
+    }
+
+    async fn upload_file_with_progress(
+        &self,
+        file_path: &Path,
+        on_progress: &(dyn Fn(u64, u64) + Send + Sync),
+        cancel: &CancellationToken
+    ) -> Result<RemoteFile, CloudError> {
+        let message = self.retry
+            .run(|| cancel.is_cancelled(), || self.telegram
+                .upload_file_with_progress(file_path, self.cloud_chat_id, on_progress, || cancel.is_cancelled())
+            )
+            .await?;
         let file_name = file_path.file_name().unwrap().to_string_lossy().to_string();
+        let remote_file = TelegramBackend::remote_file_from_message(&message);
 
-        self.files.write().await.insert(file_name, result_upload);
-        Ok(())
+        self.files.write().await.entry(file_name).or_default().push(message);
+        Ok(remote_file)
     }
 
-    async fn download_file(&self, file_path: &Path) -> Result<(), CloudError> {
+    async fn download_file_with_progress(
+        &self,
+        file_path: &Path,
+        on_progress: &(dyn Fn(u64, u64) + Send + Sync),
+        cancel: &CancellationToken
+    ) -> Result<(), CloudError> {
         let file_name = file_path.file_name().unwrap().to_string_lossy().to_string();
-        let file_id = self.files.read().await.get(&file_name).unwrap().0;
+        let file_id = match self.latest_message(&file_name).await?.document() {
+            Some(document) => document.document.id,
+            None => return Err(CloudError::RemoteFileNotFound(file_name))
+        };
+
+        self.retry.run(|| cancel.is_cancelled(), || async {
+            // Прерванное скачивание продолжается с уже скачанного TDLib начала файла
+            let local_file = self.telegram.get_file(file_id).await?.local;
+            let offset = match local_file.is_downloading_completed {
+                false => local_file.downloaded_prefix_size,
+                true => 0,
+            };
+
+            if offset > 0 {
+                println!("Продолжение скачивания {} с {} байт", file_name, offset);
+            }
 
-        let download_file = self.telegram.download_file(file_id).await.unwrap();
+            self.telegram
+                .download_file_from(file_id, offset, on_progress, || cancel.is_cancelled())
+                .await
+        }).await?;
 
         Ok(())
     }
 
+    /// Удаление всех загруженных копий файла
     async fn remove_file(&self, file_path: &Path) -> Result<(), CloudError> {
         let file_name = file_path.file_name().unwrap().to_string_lossy().to_string();
 
-        let (file_id, remove_message) = self.files.write().await.remove(&file_name).unwrap();
+        let remove_message_ids = self.files
+            .read()
+            .await
+            .get(&file_name)
+            .map(|messages| messages.iter().map(|message| message.id).collect::<Vec<_>>())
+            .ok_or(CloudError::RemoteFileNotFound(file_name.clone()))?;
 
-        let remove_message_id = remove_message["message"]["id"].as_i64().unwrap();
+        println!("Удаление {} ({:?})", file_name, remove_message_ids);
 
-        println!("{}", remove_message);
+        self.retry
+            .run(|| false, || self.telegram.delete_message(self.cloud_chat_id, &remove_message_ids))
+            .await?;
 
-        self.telegram
-            .delete_message(self.cloud_chat_id, &vec![remove_message_id]).await
-            .unwrap();
+        self.files.write().await.remove(&file_name);
+
+        Ok(())
+    }
+
+    async fn remove_remote_file(&self, remote_file: &RemoteFile) -> Result<(), CloudError> {
+        let remove_message_ids = [remote_file.id];
+
+        self.retry
+            .run(|| false, || self.telegram.delete_message(self.cloud_chat_id, &remove_message_ids))
+            .await?;
+
+        let mut files = self.files.write().await;
+        if let Some(messages) = files.get_mut(&remote_file.name) {
+            messages.retain(|message| message.id != remote_file.id);
+
+            if messages.is_empty() {
+                files.remove(&remote_file.name);
+            }
+        }
 
         Ok(())
     }
 
     async fn check_file(&self, file_name: &str) -> bool {
-        todo!()
+        self.files.read().await.contains_key(file_name)
+    }
+
+    async fn list_files(&self) -> Result<Vec<RemoteFile>, CloudError> {
+        Ok(self.files
+            .read()
+            .await
+            .values()
+            .flatten()
+            .map(TelegramBackend::remote_file_from_message)
+            .collect())
+    }
+
+    async fn pin_file(&self, file_name: &str) -> Result<(), CloudError> {
+        let message_id = self.latest_message(file_name).await?.id;
+
+        self.retry
+            .run(|| false, || self.telegram.pin_chat_message(self.cloud_chat_id, message_id))
+            .await?;
+
+        Ok(())
     }
 
     async fn close(self) -> Result<(), CloudError> {
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use tokio::sync::broadcast;
+
+/// Сколько событий хранит канал для подписчиков, не успевающих их читать
+pub const PROGRESS_CHANNEL_CAPACITY: usize = 1024;
+
+/// Этап передачи файла
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferPhase {
+    /// Разбиение файла на части перед загрузкой
+    Split,
+    Upload,
+    Download,
+    /// Сборка файла из скачанных частей
+    Join,
+}
+
+/// Прогресс передачи файла
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressEvent {
+    /// Локальный путь загружаемого файла или путь VFS скачиваемого
+    pub file: PathBuf,
+    pub phase: TransferPhase,
+    /// Часть, к которой относится событие. У этапов разбиения и сборки части нет
+    pub part: Option<String>,
+    pub part_done: u64,
+    pub part_total: u64,
+    pub file_done: u64,
+    pub file_total: u64,
+}
+
+/// Сбор прогресса частей файла, передаваемых одновременно, в события для всего файла
+pub(crate) struct FileProgress<'a> {
+    sender: &'a broadcast::Sender<ProgressEvent>,
+    file: PathBuf,
+    phase: TransferPhase,
+    /// Переданные и всего байт каждой части
+    parts: Mutex<BTreeMap<String, (u64, u64)>>,
+}
+
+impl<'a> FileProgress<'a> {
+
+    /// `parts` - имя, уже переданные байты и размер каждой части
+    pub fn new(
+        sender: &'a broadcast::Sender<ProgressEvent>,
+        file: &Path,
+        phase: TransferPhase,
+        parts: impl IntoIterator<Item = (String, u64, u64)>
+    ) -> Self {
+        Self {
+            sender,
+            file: file.to_path_buf(),
+            phase,
+            parts: Mutex::new(parts
+                .into_iter()
+                .map(|(name, done, total)| (name, (done, total)))
+                .collect()),
+        }
+    }
+
+    /// Прогресс части, неизвестный размер части (0) берется из отчета бэкенда
+    pub fn update(&self, part: &str, done: u64, total: u64) {
+        let mut parts = self.parts.lock().unwrap();
+
+        let part_progress = parts.entry(part.to_owned()).or_default();
+        part_progress.1 = part_progress.1.max(total);
+        part_progress.0 = done.min(part_progress.1);
+        let (part_done, part_total) = *part_progress;
+
+        let _ = self.sender.send(ProgressEvent {
+            file: self.file.clone(),
+            phase: self.phase,
+            part: Some(part.to_owned()),
+            part_done,
+            part_total,
+            file_done: parts.values().map(|(done, _)| done).sum(),
+            file_total: parts.values().map(|(_, total)| total).sum(),
+        });
+    }
+
+    /// Часть передана полностью
+    pub fn finish(&self, part: &str) {
+        let total = self.parts.lock().unwrap().get(part).map(|(_, total)| *total).unwrap_or_default();
+        self.update(part, total, total);
+    }
+}
+
+/// Событие этапа, который выполняется для всего файла сразу: разбиения или сборки
+pub(crate) fn file_event(file: &Path, phase: TransferPhase, done: u64, total: u64) -> ProgressEvent {
+    ProgressEvent {
+        file: file.to_path_buf(),
+        phase,
+        part: None,
+        part_done: done,
+        part_total: total,
+        file_done: done,
+        file_total: total,
+    }
+}
+
+impl Display for TransferPhase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferPhase::Split => write!(f, "разбиение"),
+            TransferPhase::Upload => write!(f, "загрузка"),
+            TransferPhase::Download => write!(f, "скачивание"),
+            TransferPhase::Join => write!(f, "сборка"),
+        }
+    }
+}
+
+impl Display for ProgressEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let percent = match self.file_total {
+            0 => 100,
+            total => self.file_done * 100 / total,
+        };
+
+        write!(f, "[{}] {} {}% ({}/{} байт)", self.phase, self.file.display(), percent, self.file_done, self.file_total)
+    }
+}
@@ -0,0 +1,305 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::folder_download::ConflictPolicy;
+
+/// Файл очереди передач в папке данных
+const QUEUE_FILE: &str = "transfer_queue.json";
+/// Блокировка очереди на время ее изменения, общая для всех процессов
+const QUEUE_LOCK_FILE: &str = "transfer_queue.lock";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "low" => Some(Self::Low),
+            "normal" => Some(Self::Normal),
+            "high" => Some(Self::High),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobKind {
+    /// Загрузка локального файла `source` в папку VFS `virtual_path`
+    Upload { source: PathBuf, virtual_path: PathBuf },
+    /// Скачивание файла VFS `virtual_path` в `destination`
+    Download { virtual_path: PathBuf, destination: PathBuf, conflicts: ConflictPolicy },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    /// Задача не запускается, пока ее не возобновят
+    Paused,
+    Done,
+    /// Текст ошибки последней попытки
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferJob {
+    pub id: u64,
+    pub kind: JobKind,
+    pub priority: Priority,
+    pub status: JobStatus,
+    /// Кол-во запусков задачи
+    pub attempts: u32,
+    /// Ограничение скорости задачи в байтах в секунду, действует вместе с общим
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueueError {
+    UnknownJob(u64),
+    /// Действие недоступно для задачи в этом состоянии
+    InvalidState(u64, JobStatus),
+}
+
+/// Очередь передач облака.
+///
+/// Очередь хранится в папке данных и может меняться несколькими процессами: каждое
+/// изменение перечитывает очередь под блокировкой файла. Выполняемую задачу держит
+/// блокировка задачи, задачи без нее прерваны перезапуском - при загрузке очереди они
+/// снова ставятся в очередь и продолжаются с помощью сессий загрузки и скачивания.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransferQueue {
+    next_id: u64,
+    jobs: Vec<TransferJob>,
+}
+
+impl TransferQueue {
+
+    /// Очередь из папки данных, без файла очереди - пустая очередь
+    pub fn load(data_dir: &Path) -> io::Result<Self> {
+        let mut queue: Self = match fs::read(data_dir.join(QUEUE_FILE)) {
+            Ok(queue) => serde_json::from_slice(&queue)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+
+        for job in queue.jobs.iter_mut().filter(|job| job.status == JobStatus::Running) {
+            if !JobLock::is_held(data_dir, job.id) {
+                job.status = JobStatus::Queued;
+            }
+        }
+
+        Ok(queue)
+    }
+
+    /// Изменение очереди в папке данных. Очередь перечитывается под блокировкой, поэтому
+    /// изменения других процессов не теряются
+    pub fn update<R>(data_dir: &Path, update: impl FnOnce(&mut Self) -> R) -> io::Result<R> {
+        let lock = File::create(data_dir.join(QUEUE_LOCK_FILE))?;
+        lock.lock()?;
+
+        let mut queue = Self::load(data_dir)?;
+        let result = update(&mut queue);
+        queue.save(data_dir)?;
+
+        Ok(result)
+    }
+
+    /// Запуск следующей задачи: задача отмечается выполняемой и блокируется, пока
+    /// блокировка не освобождена, другие процессы задачу не запустят
+    pub fn start_next(data_dir: &Path) -> io::Result<Option<(TransferJob, JobLock)>> {
+        Self::update(data_dir, |queue| {
+            let Some(id) = queue.next_job().map(|job| job.id) else {
+                return Ok(None);
+            };
+
+            let job_lock = JobLock::acquire(data_dir, id)?;
+
+            let job = queue.jobs.iter_mut().find(|job| job.id == id).unwrap();
+            job.status = JobStatus::Running;
+            job.attempts += 1;
+
+            Ok(Some((job.clone(), job_lock)))
+        })?
+    }
+
+    pub fn save(&self, data_dir: &Path) -> io::Result<()> {
+        let queue_path = data_dir.join(QUEUE_FILE);
+        let tmp_path = queue_path.with_extension("tmp");
+
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(tmp_path, queue_path)
+    }
+
+    pub fn jobs(&self) -> &[TransferJob] {
+        &self.jobs
+    }
+
+    /// Добавление задачи, возвращает id задачи
+    pub fn push(&mut self, kind: JobKind, priority: Priority) -> u64 {
+        self.next_id += 1;
+
+        self.jobs.push(TransferJob {
+            id: self.next_id,
+            kind,
+            priority,
+            status: JobStatus::Queued,
+            attempts: 0,
+            rate_limit: None,
+        });
+
+        self.next_id
+    }
+
+    /// Следующая задача: с наибольшим приоритетом, среди равных - добавленная раньше
+    pub fn next_job(&self) -> Option<&TransferJob> {
+        self.jobs
+            .iter()
+            .filter(|job| job.status == JobStatus::Queued)
+            .min_by_key(|job| (std::cmp::Reverse(job.priority), job.id))
+    }
+
+    /// Смена состояния задачи, `from` - состояния, из которых смена допустима
+    pub fn set_status(
+        &mut self,
+        id: u64,
+        from: impl Fn(&JobStatus) -> bool,
+        status: JobStatus
+    ) -> Result<(), QueueError> {
+        let job = self.jobs
+            .iter_mut()
+            .find(|job| job.id == id)
+            .ok_or(QueueError::UnknownJob(id))?;
+
+        if !from(&job.status) {
+            return Err(QueueError::InvalidState(id, job.status.clone()));
+        }
+
+        if status == JobStatus::Running {
+            job.attempts += 1;
+        }
+
+        job.status = status;
+        Ok(())
+    }
+
+    /// Приостановка задачи. Выполняемая задача отменяется процессом, который ее выполняет
+    pub fn pause(&mut self, id: u64) -> Result<(), QueueError> {
+        self.set_status(id, |status| matches!(status, JobStatus::Queued | JobStatus::Running), JobStatus::Paused)
+    }
+
+    pub fn resume(&mut self, id: u64) -> Result<(), QueueError> {
+        self.set_status(id, |status| *status == JobStatus::Paused, JobStatus::Queued)
+    }
+
+    pub fn retry(&mut self, id: u64) -> Result<(), QueueError> {
+        self.set_status(id, |status| matches!(status, JobStatus::Failed(_)), JobStatus::Queued)
+    }
+
+    /// Ограничение скорости задачи, выполняемой задаче оно применится при следующем запуске
+    pub fn set_rate_limit(&mut self, id: u64, rate_limit: Option<u64>) -> Result<(), QueueError> {
+        let job = self.jobs
+            .iter_mut()
+            .find(|job| job.id == id)
+            .ok_or(QueueError::UnknownJob(id))?;
+
+        job.rate_limit = rate_limit;
+        Ok(())
+    }
+
+    /// Удаление выполненных задач
+    pub fn clear_done(&mut self) {
+        self.jobs.retain(|job| job.status != JobStatus::Done);
+    }
+}
+
+/// Блокировка выполняемой задачи, освобождается вместе с файлом блокировки
+#[derive(Debug)]
+pub struct JobLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl JobLock {
+
+    fn path(data_dir: &Path, id: u64) -> PathBuf {
+        data_dir.join(format!("transfer_job_{}.lock", id))
+    }
+
+    fn acquire(data_dir: &Path, id: u64) -> io::Result<Self> {
+        let path = Self::path(data_dir, id);
+        let file = File::create(&path)?;
+        file.try_lock().map_err(io::Error::from)?;
+
+        Ok(Self { file, path })
+    }
+
+    /// Выполняет ли задачу какой-либо процесс
+    fn is_held(data_dir: &Path, id: u64) -> bool {
+        match File::open(Self::path(data_dir, id)) {
+            Ok(file) => matches!(file.try_lock(), Err(fs::TryLockError::WouldBlock)),
+            Err(_) => false,
+        }
+    }
+}
+
+impl Drop for JobLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        let _ = self.file.unlock();
+    }
+}
+
+/// Результат обработки очереди
+#[derive(Debug, Default)]
+pub struct QueueReport {
+    pub done: Vec<u64>,
+    pub failed: Vec<(u64, String)>,
+}
+
+impl Display for TransferJob {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "#{} [{:?}] ", self.id, self.priority)?;
+
+        if let Some(rate_limit) = self.rate_limit {
+            write!(f, "[{} КБ/с] ", rate_limit / 1024)?;
+        }
+
+        match &self.kind {
+            JobKind::Upload { source, virtual_path } =>
+                write!(f, "загрузка {} -> {}", source.display(), virtual_path.display())?,
+            JobKind::Download { virtual_path, destination, .. } =>
+                write!(f, "скачивание {} -> {}", virtual_path.display(), destination.display())?,
+        }
+
+        match &self.status {
+            JobStatus::Queued => write!(f, " - в очереди"),
+            JobStatus::Running => write!(f, " - выполняется"),
+            JobStatus::Paused => write!(f, " - приостановлена"),
+            JobStatus::Done => write!(f, " - выполнена"),
+            JobStatus::Failed(error) => write!(f, " - ошибка после {} попыток: {}", self.attempts, error),
+        }
+    }
+}
+
+impl Display for QueueReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Выполнено задач: {}", self.done.len())?;
+
+        writeln!(f, "Ошибок: {}", self.failed.len())?;
+        for (id, error) in &self.failed {
+            writeln!(f, "  ! #{} - {}", id, error)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use telegram_drive_file::file_separation::SeparationFile;
+
+use crate::virtual_file_system::{modified_secs, Checksum, Metadata};
+
+/// Папка с сессиями незавершенных загрузок внутри папки данных
+const SESSIONS_DIR: &str = "upload_sessions";
+
+/// Часть файла, загружаемая в рамках сессии
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionPart {
+    pub name: String,
+    pub checksum: Checksum,
+    /// id файла в облаке, `None` - часть еще не загружена
+    pub remote_id: Option<i64>,
+}
+
+/// Незавершенная загрузка файла.
+///
+/// Сессия сохраняется на диск после каждой загруженной части, поэтому после перезапуска
+/// загрузка продолжается с первой незагруженной части. Файл попадает в VFS только
+/// когда загружены все части и метафайл.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub id: String,
+    /// Абсолютный путь до загружаемого файла
+    pub source_path: PathBuf,
+    /// Папка VFS, в которую загружается файл
+    pub virtual_path: PathBuf,
+    /// Размер и время изменения файла при разбиении, по ним проверяется, что файл не изменился
+    pub source_size: u64,
+    pub source_modified: Option<u64>,
+    pub content: Checksum,
+    pub filename: String,
+    pub extension: String,
+    pub metafile: SessionPart,
+    pub parts: Vec<SessionPart>,
+    /// Загруженный файл заменяет файл с тем же именем в VFS
+    #[serde(default)]
+    pub replace: bool,
+}
+
+impl UploadSession {
+
+    /// Новая сессия для уже разбитого на части файла, части лежат в `work_dir`
+    pub fn new(
+        source_path: &Path,
+        virtual_path: &Path,
+        separation_file: &SeparationFile,
+        work_dir: &Path
+    ) -> io::Result<Self> {
+
+        let source_path = fs::canonicalize(source_path)?;
+        let source_metadata = fs::metadata(&source_path)?;
+
+        let session_part = |name: &String| Ok::<_, io::Error>(SessionPart {
+            name: name.clone(),
+            checksum: Checksum::of_file(&work_dir.join(name))?,
+            remote_id: None,
+        });
+
+        Ok(Self {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            source_size: source_metadata.len(),
+            source_modified: modified_secs(&source_metadata),
+            content: Checksum::of_file(&source_path)?,
+            source_path,
+            virtual_path: virtual_path.to_path_buf(),
+            filename: separation_file.filename.clone(),
+            extension: separation_file.file_extension.clone(),
+            metafile: session_part(&separation_file.metafile)?,
+            parts: separation_file.parts
+                .iter()
+                .map(|part| session_part(&part.part_file_name))
+                .collect::<Result<_, _>>()?,
+            replace: false,
+        })
+    }
+
+    /// Все сохраненные сессии, поврежденные файлы сессий пропускаются
+    pub fn load_all(data_dir: &Path) -> Vec<Self> {
+        let Ok(entries) = fs::read_dir(data_dir.join(SESSIONS_DIR)) else {
+            return vec![];
+        };
+
+        let mut sessions = entries
+            .flatten()
+            .filter(|entry| entry.path().extension().is_some_and(|extension| extension == "json"))
+            .filter_map(|entry| fs::read(entry.path()).ok())
+            .filter_map(|session| serde_json::from_slice::<Self>(&session).ok())
+            .collect::<Vec<_>>();
+
+        sessions.sort_by(|a, b| a.source_path.cmp(&b.source_path));
+        sessions
+    }
+
+    /// Сессия загрузки файла `source_path` в папку VFS `virtual_path`
+    pub fn find(data_dir: &Path, source_path: &Path, virtual_path: &Path) -> Option<Self> {
+        let source_path = fs::canonicalize(source_path).ok()?;
+
+        Self::load_all(data_dir)
+            .into_iter()
+            .find(|session| session.source_path == source_path && session.virtual_path == virtual_path)
+    }
+
+    pub fn save(&self, data_dir: &Path) -> io::Result<()> {
+        let session_path = Self::path(data_dir, &self.id);
+        let tmp_path = session_path.with_extension("tmp");
+
+        fs::create_dir_all(session_path.parent().unwrap())?;
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(tmp_path, session_path)
+    }
+
+    pub fn remove(&self, data_dir: &Path) -> io::Result<()> {
+        match fs::remove_file(Self::path(data_dir, &self.id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Исходный файл не менялся с момента разбиения
+    pub fn is_source_unchanged(&self) -> bool {
+        fs::metadata(&self.source_path).is_ok_and(|metadata|
+            metadata.len() == self.source_size && modified_secs(&metadata) == self.source_modified
+        )
+    }
+
+    /// Части и метафайл сессии
+    pub fn files(&self) -> impl Iterator<Item = &SessionPart> {
+        self.parts.iter().chain([&self.metafile])
+    }
+
+    pub fn files_mut(&mut self) -> impl Iterator<Item = &mut SessionPart> {
+        self.parts.iter_mut().chain([&mut self.metafile])
+    }
+
+    /// Имена и размеры еще не загруженных частей без метафайла, он загружается последним
+    pub fn missing_parts(&self) -> Vec<(String, u64)> {
+        self.parts
+            .iter()
+            .filter(|part| part.remote_id.is_none())
+            .map(|part| (part.name.clone(), part.checksum.size))
+            .collect()
+    }
+
+    pub fn uploaded_parts(&self) -> usize {
+        self.parts.iter().filter(|part| part.remote_id.is_some()).count()
+    }
+
+    /// Отметка части или метафайла загруженными
+    pub fn confirm(&mut self, name: &str, remote_id: i64) {
+        if let Some(part) = self.files_mut().find(|part| part.name == name) {
+            part.remote_id = Some(remote_id);
+        }
+    }
+
+    /// Метаданные файла VFS: контрольные суммы частей и атрибуты исходного файла
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        let mut metadata = Metadata::default();
+        metadata.record_attributes(&fs::metadata(&self.source_path)?);
+        metadata.content = Some(self.content.clone());
+
+        for part in self.files() {
+            metadata.checksums.insert(part.name.clone(), part.checksum.clone());
+        }
+
+        Ok(metadata)
+    }
+
+    fn path(data_dir: &Path, id: &str) -> PathBuf {
+        data_dir.join(SESSIONS_DIR).join(format!("{}.json", id))
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::virtual_file_system::{FileSystemNode, VirtualFileSystem};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    File,
+    Folder,
+}
+
+impl From<&FileSystemNode> for NodeKind {
+    fn from(value: &FileSystemNode) -> Self {
+        match value {
+            FileSystemNode::File(_) => NodeKind::File,
+            FileSystemNode::Folder(_) => NodeKind::Folder,
+        }
+    }
+}
+
+/// Одно изменение между двумя состояниями VFS
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum VFSChange {
+    Added { path: PathBuf, kind: NodeKind },
+    Removed { path: PathBuf, kind: NodeKind },
+    Modified { path: PathBuf },
+    Moved { from: PathBuf, to: PathBuf, kind: NodeKind },
+    Renamed { from: PathBuf, to: PathBuf, kind: NodeKind },
+}
+
+/// Набор изменений, превращающий старую VFS в новую
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VFSChangeSet {
+    pub changes: Vec<VFSChange>,
+}
+
+impl VFSChangeSet {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+/// Сравнение двух VFS.
+///
+/// Файлы сопоставляются по имени метафайла сборки: один и тот же метафайл по другому пути
+/// означает перемещение или переименование. Папка считается перемещенной, если набор
+/// метафайлов внутри нее не изменился.
+pub fn diff(old: &VirtualFileSystem, new: &VirtualFileSystem) -> VFSChangeSet {
+
+    let old_nodes = old.nodes().into_iter().collect::<BTreeMap<_, _>>();
+    let new_nodes = new.nodes().into_iter().collect::<BTreeMap<_, _>>();
+
+    let mut removed = BTreeMap::new();
+    let mut added = BTreeMap::new();
+    let mut changes = vec![];
+
+    for (path, old_node) in &old_nodes {
+        match (old_node, new_nodes.get(path)) {
+            (_, None) => { removed.insert(path.clone(), *old_node); },
+
+            (FileSystemNode::File(old_file), Some(FileSystemNode::File(new_file))) => {
+                if old_file.build_metafile != new_file.build_metafile
+                    || old_file.parts_name != new_file.parts_name
+                {
+                    changes.push(VFSChange::Modified { path: path.clone() });
+                }
+            },
+
+            (FileSystemNode::Folder(_), Some(FileSystemNode::Folder(_))) => {},
+
+            // Файл заменен папкой или наоборот
+            (_, Some(new_node)) => {
+                removed.insert(path.clone(), *old_node);
+                added.insert(path.clone(), *new_node);
+            }
+        }
+    }
+
+    for (path, new_node) in &new_nodes {
+        if !old_nodes.contains_key(path) {
+            added.insert(path.clone(), *new_node);
+        }
+    }
+
+    // Сначала ищутся перемещенные папки, чтобы не выводить изменения всего их содержимого
+    let mut moved_folders: Vec<(PathBuf, PathBuf)> = vec![];
+
+    for (from, node) in &removed {
+        if !matches!(node, FileSystemNode::Folder(_))
+            || moved_folders.iter().any(|(moved_from, _)| from.starts_with(moved_from))
+        {
+            continue;
+        }
+
+        let old_content = folder_metafiles(&old_nodes, from);
+        if old_content.is_empty() {
+            continue;
+        }
+
+        let target = added
+            .iter()
+            .filter(|(to, node)|
+                matches!(node, FileSystemNode::Folder(_))
+                    && !moved_folders.iter().any(|(_, moved_to)| to.starts_with(moved_to))
+            )
+            .map(|(to, _)| to)
+            .find(|to| folder_metafiles(&new_nodes, to) == old_content);
+
+        if let Some(to) = target {
+            moved_folders.push((from.clone(), to.clone()));
+        }
+    }
+
+    for (from, to) in &moved_folders {
+        removed.retain(|path, _| !path.starts_with(from));
+        added.retain(|path, _| !path.starts_with(to));
+        changes.push(move_change(from, to, NodeKind::Folder));
+    }
+
+    // Затем перемещенные и переименованные файлы
+    let mut matched_added = BTreeSet::new();
+
+    removed.retain(|from, node| {
+        let FileSystemNode::File(old_file) = node else {
+            return true;
+        };
+
+        let target = added
+            .iter()
+            .filter(|(to, _)| !matched_added.contains(*to))
+            .find(|(_, node)| matches!(
+                node,
+                FileSystemNode::File(new_file) if new_file.build_metafile == old_file.build_metafile
+            ))
+            .map(|(to, _)| to.clone());
+
+        match target {
+            Some(to) => {
+                changes.push(move_change(from, &to, NodeKind::File));
+                matched_added.insert(to);
+                false
+            },
+            None => true
+        }
+    });
+
+    added.retain(|path, _| !matched_added.contains(path));
+
+    changes.extend(removed.iter().map(|(path, node)| VFSChange::Removed {
+        path: path.clone(),
+        kind: NodeKind::from(*node),
+    }));
+    changes.extend(added.iter().map(|(path, node)| VFSChange::Added {
+        path: path.clone(),
+        kind: NodeKind::from(*node),
+    }));
+
+    VFSChangeSet { changes }
+}
+
+/// Метафайлы всех файлов внутри папки (пути относительно папки)
+fn folder_metafiles(
+    nodes: &BTreeMap<PathBuf, &FileSystemNode>,
+    folder_path: &Path
+) -> BTreeSet<(PathBuf, String)> {
+    nodes
+        .iter()
+        .filter_map(|(path, node)| match node {
+            FileSystemNode::File(file) => path
+                .strip_prefix(folder_path)
+                .ok()
+                .map(|relative| (relative.to_path_buf(), file.build_metafile.clone())),
+            FileSystemNode::Folder(_) => None,
+        })
+        .collect()
+}
+
+fn move_change(from: &Path, to: &Path, kind: NodeKind) -> VFSChange {
+    if from.parent() == to.parent() {
+        VFSChange::Renamed { from: from.to_path_buf(), to: to.to_path_buf(), kind }
+    } else {
+        VFSChange::Moved { from: from.to_path_buf(), to: to.to_path_buf(), kind }
+    }
+}
+
+impl Display for VFSChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            VFSChange::Added { path, kind } =>
+                write!(f, "+ {:?} {}", kind, path.display()),
+            VFSChange::Removed { path, kind } =>
+                write!(f, "- {:?} {}", kind, path.display()),
+            VFSChange::Modified { path } =>
+                write!(f, "~ File {}", path.display()),
+            VFSChange::Moved { from, to, kind } =>
+                write!(f, "> {:?} {} -> {}", kind, from.display(), to.display()),
+            VFSChange::Renamed { from, to, kind } =>
+                write!(f, "* {:?} {} -> {}", kind, from.display(), to.display()),
+        }
+    }
+}
+
+impl Display for VFSChangeSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            return writeln!(f, "Изменений нет");
+        }
+
+        for change in &self.changes {
+            writeln!(f, "{}", change)?;
+        }
+
+        Ok(())
+    }
+}
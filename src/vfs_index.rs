@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+use argon2::Argon2;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::aead::rand_core::RngCore;
+
+use crate::virtual_file_system::VirtualFileSystem;
+
+/// Заголовок зашифрованного снимка VFS
+const INDEX_MAGIC: &[u8] = b"TGDVFS1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+const INDEX_PREFIX: &str = "vfs_index_";
+const INDEX_EXTENSION: &str = ".vfsidx";
+
+#[derive(Debug)]
+pub enum VFSIndexError {
+    /// Не задан пароль для шифрования снимков
+    NoPassphrase,
+    InvalidFormat,
+    /// Неверный ключ или поврежденные данные
+    DecryptError,
+    KeyDerivationError,
+    SerdeError(serde_json::Error),
+}
+
+impl From<serde_json::Error> for VFSIndexError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::SerdeError(value)
+    }
+}
+
+impl Display for VFSIndexError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for VFSIndexError { }
+
+/// Имя файла снимка VFS версии `version`, выгруженного устройством `device_id`
+pub fn index_file_name(version: u64, device_id: &str) -> String {
+    format!("{}{:010}_{}{}", INDEX_PREFIX, version, device_id, INDEX_EXTENSION)
+}
+
+/// Версия снимка по имени файла, `None` - файл не является снимком VFS
+pub fn parse_index_version(file_name: &str) -> Option<u64> {
+    parse_index_name(file_name).map(|(version, _)| version)
+}
+
+/// Версия и устройство снимка по имени файла
+pub fn parse_index_name(file_name: &str) -> Option<(u64, String)> {
+    let (version, device_id) = file_name
+        .strip_prefix(INDEX_PREFIX)?
+        .strip_suffix(INDEX_EXTENSION)?
+        .split_once('_')?;
+
+    Some((version.parse().ok()?, device_id.to_owned()))
+}
+
+/// Снимки, которые нужно влить: последний снимок каждого устройства, если он новее
+/// влитого ранее снимка этого устройства. Снимки разных устройств сравниваются
+/// отдельно, поэтому одновременные выгрузки с одинаковой версией не теряются.
+/// Возвращает версию, устройство и имя файла снимка
+pub fn snapshots_to_pull(
+    file_names: &[String],
+    pulled_versions: &HashMap<String, u64>
+) -> Vec<(u64, String, String)> {
+
+    let mut latest: HashMap<String, (u64, &String)> = HashMap::new();
+
+    for file_name in file_names {
+        if let Some((version, device_id)) = parse_index_name(file_name) {
+            let entry = latest.entry(device_id).or_insert((version, file_name));
+            if version > entry.0 {
+                *entry = (version, file_name);
+            }
+        }
+    }
+
+    let mut snapshots = latest
+        .into_iter()
+        .filter(|(device_id, (version, _))| pulled_versions.get(device_id).is_none_or(|pulled| version > pulled))
+        .map(|(device_id, (version, file_name))| (version, device_id, file_name.clone()))
+        .collect::<Vec<_>>();
+
+    snapshots.sort();
+    snapshots
+}
+
+/// Шифрование снимка VFS ключом, полученным из пароля.
+///
+/// Формат: `TGDVFS1 | соль (16 байт) | nonce (12 байт) | json VFS, зашифрованный ChaCha20-Poly1305`
+pub fn encrypt_index(vfs: &VirtualFileSystem, passphrase: &str) -> Result<Vec<u8>, VFSIndexError> {
+
+    let mut salt = [0_u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let vfs_json = serde_json::to_vec(vfs)?;
+
+    let ciphertext = cipher
+        .encrypt(&nonce, vfs_json.as_slice())
+        .map_err(|_| VFSIndexError::DecryptError)?;
+
+    let mut output = Vec::with_capacity(INDEX_MAGIC.len() + SALT_LEN + NONCE_LEN + ciphertext.len());
+    output.extend_from_slice(INDEX_MAGIC);
+    output.extend_from_slice(&salt);
+    output.extend_from_slice(&nonce);
+    output.extend_from_slice(&ciphertext);
+
+    Ok(output)
+}
+
+/// Расшифровка снимка VFS, созданного `encrypt_index`
+pub fn decrypt_index(data: &[u8], passphrase: &str) -> Result<VirtualFileSystem, VFSIndexError> {
+
+    let data = data
+        .strip_prefix(INDEX_MAGIC)
+        .ok_or(VFSIndexError::InvalidFormat)?;
+
+    if data.len() < SALT_LEN + NONCE_LEN {
+        return Err(VFSIndexError::InvalidFormat);
+    }
+
+    let (salt, data) = data.split_at(SALT_LEN);
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, salt)?);
+
+    let vfs_json = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| VFSIndexError::DecryptError)?;
+
+    Ok(serde_json::from_slice(&vfs_json)?)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key, VFSIndexError> {
+    let mut key = Key::default();
+
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_| VFSIndexError::KeyDerivationError)?;
+
+    Ok(key)
+}
@@ -0,0 +1,297 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+
+use crate::virtual_file_system::{
+    path_key, ClockOrdering, FileSystemNode, VFSFolder, VectorClock, VirtualFileSystem
+};
+
+/// Конфликт, обнаруженный при слиянии
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeConflict {
+    /// Узел изменен на обоих устройствах, удаленная версия сохранена как копия
+    ConflictCopy { path: PathBuf, copy_path: PathBuf },
+    /// Узел удален на одном устройстве и изменен на другом, изменения сохранены
+    DeletedAndModified { path: PathBuf },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MergeReport {
+    /// Узлы, добавленные или обновленные из удаленной VFS
+    pub applied: Vec<PathBuf>,
+    /// Узлы, удаленные, так как их удалили на другом устройстве
+    pub removed: Vec<PathBuf>,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+impl MergeReport {
+    pub fn is_empty(&self) -> bool {
+        self.applied.is_empty() && self.removed.is_empty() && self.conflicts.is_empty()
+    }
+}
+
+/// Слияние локальной VFS с VFS другого устройства.
+///
+/// Ревизии узлов сравниваются векторными часами: более новая версия побеждает, а
+/// одновременные изменения одного пути сохраняются обе - удаленная версия кладется
+/// рядом как копия `имя (conflict устройство)`, где устройство - автор удаленной версии.
+/// Удаление никогда не затирает одновременное изменение. Надгробия, которые видели все
+/// известные устройства, после слияния удаляются.
+pub fn merge(
+    local: &VirtualFileSystem,
+    remote: &VirtualFileSystem,
+    device_id: &str
+) -> (VirtualFileSystem, MergeReport) {
+
+    let local_nodes = flat_nodes(local);
+    let remote_nodes = flat_nodes(remote);
+
+    let mut report = MergeReport::default();
+    let mut result: BTreeMap<PathBuf, FileSystemNode> = BTreeMap::new();
+
+    let paths = local_nodes
+        .keys()
+        .chain(remote_nodes.keys())
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    for path in paths {
+        match (local_nodes.get(&path), remote_nodes.get(&path)) {
+            (Some(local_node), Some(remote_node)) => {
+                let ordering = local_node.metadata().clock.compare(&remote_node.metadata().clock);
+
+                match (local_node, remote_node, ordering) {
+                    (FileSystemNode::Folder(_), FileSystemNode::Folder(_), _) => {
+                        let mut folder = local_node.clone();
+                        folder.metadata_mut().clock.merge(&remote_node.metadata().clock);
+                        result.insert(path, folder);
+                    },
+
+                    (_, _, ClockOrdering::Equal | ClockOrdering::After) => {
+                        result.insert(path, local_node.clone());
+                    },
+
+                    (_, _, ClockOrdering::Before) => {
+                        report.applied.push(path.clone());
+                        result.insert(path, remote_node.clone());
+                    },
+
+                    (FileSystemNode::File(local_file), FileSystemNode::File(remote_file), ClockOrdering::Concurrent)
+                        if local_file.build_metafile == remote_file.build_metafile =>
+                    {
+                        let mut file = local_node.clone();
+                        file.metadata_mut().clock.merge(&remote_node.metadata().clock);
+                        result.insert(path, file);
+                    },
+
+                    (_, _, ClockOrdering::Concurrent) => {
+                        let writer = remote_writer(&local_node.metadata().clock, &remote_node.metadata().clock)
+                            .unwrap_or(device_id);
+
+                        let copy_path = conflict_copy_path(&path, remote_node, writer, |candidate|
+                            local_nodes.contains_key(candidate) || remote_nodes.contains_key(candidate)
+                        );
+
+                        let mut copy = remote_node.clone();
+                        rename_node(&mut copy, &copy_path);
+
+                        // Локальная версия теперь учитывает удаленную, сохраненную копией
+                        let mut local_node = local_node.clone();
+                        local_node.metadata_mut().clock.merge(&remote_node.metadata().clock);
+
+                        report.conflicts.push(MergeConflict::ConflictCopy {
+                            path: path.clone(),
+                            copy_path: copy_path.clone(),
+                        });
+                        result.insert(path, local_node);
+                        result.insert(copy_path, copy);
+                    }
+                }
+            },
+
+            (None, Some(remote_node)) => {
+                let tombstone = tombstone_for(local, &path);
+
+                match tombstone.map(|tombstone| tombstone.compare(&remote_node.metadata().clock)) {
+                    // Локальное удаление новее удаленной версии
+                    Some(ClockOrdering::Equal | ClockOrdering::After) => {},
+                    Some(ClockOrdering::Concurrent) => {
+                        let mut remote_node = remote_node.clone();
+                        remote_node.metadata_mut().clock.merge(tombstone.unwrap());
+
+                        report.conflicts.push(MergeConflict::DeletedAndModified { path: path.clone() });
+                        result.insert(path, remote_node);
+                    },
+                    Some(ClockOrdering::Before) | None => {
+                        report.applied.push(path.clone());
+                        result.insert(path, remote_node.clone());
+                    }
+                }
+            },
+
+            (Some(local_node), None) => {
+                let tombstone = tombstone_for(remote, &path);
+
+                match tombstone.map(|tombstone| tombstone.compare(&local_node.metadata().clock)) {
+                    Some(ClockOrdering::Equal | ClockOrdering::After) => {
+                        report.removed.push(path.clone());
+                    },
+                    Some(ClockOrdering::Concurrent) => {
+                        let mut local_node = local_node.clone();
+                        local_node.metadata_mut().clock.merge(tombstone.unwrap());
+
+                        report.conflicts.push(MergeConflict::DeletedAndModified { path: path.clone() });
+                        result.insert(path, local_node);
+                    },
+                    Some(ClockOrdering::Before) | None => {
+                        result.insert(path, local_node.clone());
+                    }
+                }
+            },
+
+            (None, None) => unreachable!()
+        }
+    }
+
+    let mut merged = VirtualFileSystem::new(local.options.clone());
+
+    // Надгробия объединяются, кроме путей, которые существуют после слияния
+    let mut tombstones: HashMap<String, VectorClock> = local.tombstones.clone();
+    for (key, clock) in &remote.tombstones {
+        tombstones.entry(key.clone()).or_default().merge(clock);
+    }
+
+    for (path, node) in result {
+        let inserted_path = insert_node(&mut merged, &path, node, device_id);
+        tombstones.remove(&path_key(&inserted_path));
+    }
+
+    merged.tombstones = tombstones;
+
+    merged.seen = local.seen.clone();
+    for (seen_device_id, clock) in &remote.seen {
+        merged.seen.entry(seen_device_id.clone()).or_default().merge(clock);
+    }
+    merged.record_seen(device_id);
+    merged.prune_tombstones();
+
+    (merged, report)
+}
+
+/// Все узлы VFS по путям, папки без детей
+fn flat_nodes(vfs: &VirtualFileSystem) -> BTreeMap<PathBuf, FileSystemNode> {
+    vfs.nodes()
+        .into_iter()
+        .map(|(path, node)| {
+            let node = match node {
+                FileSystemNode::File(file) => FileSystemNode::File(file.clone()),
+                FileSystemNode::Folder(folder) => FileSystemNode::Folder(VFSFolder {
+                    name: folder.name.clone(),
+                    metadata: folder.metadata.clone(),
+                    children: Default::default(),
+                }),
+            };
+            (path, node)
+        })
+        .collect()
+}
+
+/// Надгробие узла или ближайшей удаленной родительской папки
+fn tombstone_for<'a>(vfs: &'a VirtualFileSystem, path: &Path) -> Option<&'a VectorClock> {
+    path.ancestors()
+        .take_while(|ancestor| ancestor.iter().count() > 1)
+        .find_map(|ancestor| vfs.tombstones.get(&path_key(ancestor)))
+}
+
+/// Вставка узла по пути, возвращает путь, по которому узел оказался.
+///
+/// Отсутствующие родительские папки создаются, чтобы не потерять узел, родителя которого
+/// удалили на другом устройстве. Если родитель стал файлом, узел кладется в папку-копию.
+fn insert_node(vfs: &mut VirtualFileSystem, path: &Path, node: FileSystemNode, device_id: &str) -> PathBuf {
+    let mut parent = path.parent().unwrap_or(Path::new("fs://")).to_path_buf();
+
+    if vfs.get_folder(&parent).is_err() {
+        let parent_node = FileSystemNode::Folder(VFSFolder {
+            name: parent.file_name().unwrap().to_string_lossy().to_string(),
+            metadata: Default::default(),
+            children: Default::default(),
+        });
+
+        parent = match vfs.get_node(&parent) {
+            Ok(FileSystemNode::File(_)) => {
+                let copy_path = conflict_copy_path(&parent, &parent_node, device_id, |_| false);
+                let mut copy = parent_node;
+                rename_node(&mut copy, &copy_path);
+                insert_node(vfs, &copy_path, copy, device_id)
+            },
+            _ => insert_node(vfs, &parent, parent_node, device_id)
+        };
+    }
+
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+
+    vfs.get_mut_folder(&parent)
+        .unwrap()
+        .children
+        .entry(name)
+        .or_insert(node);
+
+    parent.join(path.file_name().unwrap())
+}
+
+/// Устройство, изменившее удаленную версию узла одновременно с локальной:
+/// его счетчик в удаленных часах больше, чем в локальных
+fn remote_writer<'a>(local: &VectorClock, remote: &'a VectorClock) -> Option<&'a str> {
+    remote.0
+        .iter()
+        .filter(|(remote_device_id, counter)| local.0.get(*remote_device_id).is_none_or(|local_counter| *counter > local_counter))
+        .max_by_key(|(_, counter)| **counter)
+        .map(|(remote_device_id, _)| remote_device_id.as_str())
+}
+
+fn conflict_copy_path(
+    path: &Path,
+    node: &FileSystemNode,
+    device_id: &str,
+    is_taken: impl Fn(&Path) -> bool
+) -> PathBuf {
+    let device_short = device_id.chars().take(8).collect::<String>();
+
+    (1..)
+        .map(|index| {
+            let suffix = if index == 1 { String::new() } else { format!(" {}", index) };
+            path.with_file_name(format!("{} (conflict {}{})", node.name(), device_short, suffix))
+        })
+        .find(|candidate| !is_taken(candidate))
+        .unwrap()
+}
+
+fn rename_node(node: &mut FileSystemNode, path: &Path) {
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+
+    match node {
+        FileSystemNode::File(file) => file.name = name,
+        FileSystemNode::Folder(folder) => folder.name = name,
+    }
+}
+
+impl Display for MergeReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for path in &self.applied {
+            writeln!(f, "< {}", path.display())?;
+        }
+        for path in &self.removed {
+            writeln!(f, "- {}", path.display())?;
+        }
+        for conflict in &self.conflicts {
+            match conflict {
+                MergeConflict::ConflictCopy { path, copy_path } =>
+                    writeln!(f, "! {} изменен на обоих устройствах, копия: {}", path.display(), copy_path.display())?,
+                MergeConflict::DeletedAndModified { path } =>
+                    writeln!(f, "! {} удален на одном устройстве и изменен на другом, сохранен", path.display())?,
+            }
+        }
+
+        Ok(())
+    }
+}
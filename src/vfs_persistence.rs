@@ -0,0 +1,426 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Serialize, Deserialize};
+
+use crate::virtual_file_system::{path_key, FSOption, FileSystemNode, VFSError, VFSFile, VFSFolder, VectorClock, VirtualFileSystem};
+
+/// Мутация VFS, записываемая в журнал
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum VFSOperation {
+    AddFile { path: PathBuf, file: VFSFile },
+    AddFolder { path: PathBuf, folder: VFSFolder },
+    RemoveNode {
+        path: PathBuf,
+        /// Ревизия удаления, сохраняется в VFS как надгробие узла
+        #[serde(default)]
+        clock: VectorClock,
+    },
+    /// Перенос узла `path` в папку `destination`. `node` - переносимый узел
+    /// с ревизией и именем на новом месте
+    MoveNode {
+        path: PathBuf,
+        destination: PathBuf,
+        node: FileSystemNode,
+        /// Ревизия удаления со старого места, сохраняется как надгробие
+        #[serde(default)]
+        clock: VectorClock,
+    },
+}
+
+impl VFSOperation {
+    pub fn apply(&self, vfs: &mut VirtualFileSystem) -> Result<(), VFSError> {
+        match self {
+            VFSOperation::AddFile { path, file } => {
+                vfs.add_file(path, file.clone())?;
+                vfs.tombstones.remove(&path_key(&path.join(&file.name)));
+            },
+            VFSOperation::AddFolder { path, folder } => {
+                vfs.add_folder(path, folder.clone())?;
+                vfs.tombstones.remove(&path_key(&path.join(&folder.name)));
+            },
+            VFSOperation::RemoveNode { path, clock } => {
+                vfs.remove_node(path)?;
+
+                if !clock.is_empty() {
+                    vfs.tombstones.insert(path_key(path), clock.clone());
+                }
+            },
+            VFSOperation::MoveNode { path, destination, node, clock } => {
+                check_move(path, destination)?;
+
+                // Место назначения проверяется до удаления, чтобы перенос не остановился на середине
+                if vfs.get_folder(destination)?.children.contains_key(node.name()) {
+                    return Err(match node {
+                        FileSystemNode::File(_) => VFSError::FileAlreadyExists,
+                        FileSystemNode::Folder(_) => VFSError::FolderAlreadyExists,
+                    });
+                }
+
+                vfs.remove_node(path)?;
+                vfs.get_mut_folder(destination)?.children.insert(node.name().to_owned(), node.clone());
+
+                vfs.tombstones.remove(&path_key(&destination.join(node.name())));
+                if !clock.is_empty() {
+                    vfs.tombstones.insert(path_key(path), clock.clone());
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Пути узлов, которые меняет операция
+    fn touched_paths(&self) -> Vec<PathBuf> {
+        match self {
+            VFSOperation::AddFile { path, file } => vec![path.join(&file.name)],
+            VFSOperation::AddFolder { path, folder } => vec![path.join(&folder.name)],
+            VFSOperation::RemoveNode { path, .. } => vec![path.clone()],
+            VFSOperation::MoveNode { path, destination, node, .. } => vec![path.clone(), destination.join(node.name())],
+        }
+    }
+
+    /// Запоминание узлов и надгробий, которые поменяет операция, до ее применения
+    pub fn rollback(&self, vfs: &VirtualFileSystem) -> Rollback {
+        Rollback {
+            nodes: self.touched_paths()
+                .into_iter()
+                .map(|path| {
+                    let node = vfs.get_node(&path).ok().cloned();
+                    let tombstone = vfs.tombstones.get(&path_key(&path)).cloned();
+                    (path, node, tombstone)
+                })
+                .collect()
+        }
+    }
+
+    /// Проставление ревизии операции от имени устройства `device_id`.
+    ///
+    /// Новая ревизия продолжает ревизию заменяемого или удаляемого узла, поэтому
+    /// она всегда новее всего, что устройство видело по этому пути.
+    pub fn stamp(&mut self, vfs: &VirtualFileSystem, device_id: &str) {
+        match self {
+            VFSOperation::AddFile { path, file } =>
+                stamp_clock(vfs, &path.join(&file.name), &mut file.metadata.clock, device_id),
+            VFSOperation::AddFolder { path, folder } =>
+                stamp_clock(vfs, &path.join(&folder.name), &mut folder.metadata.clock, device_id),
+            VFSOperation::RemoveNode { path, clock } =>
+                stamp_clock(vfs, path, clock, device_id),
+            // Перенос - удаление со старого места и добавление на новое
+            VFSOperation::MoveNode { path, destination, node, clock } => {
+                stamp_clock(vfs, path, clock, device_id);
+                let node_path = destination.join(node.name());
+                stamp_clock(vfs, &node_path, &mut node.metadata_mut().clock, device_id);
+            },
+        }
+    }
+}
+
+/// Прежнее состояние узлов, затронутых операцией. Позволяет отменить операцию
+/// в VFS, не копируя все дерево
+#[derive(Debug)]
+pub struct Rollback {
+    nodes: Vec<(PathBuf, Option<FileSystemNode>, Option<VectorClock>)>,
+}
+
+impl Rollback {
+    pub fn restore(self, vfs: &mut VirtualFileSystem) {
+        // Обратный порядок: при переносе сначала убирается узел с нового места
+        for (path, node, tombstone) in self.nodes.into_iter().rev() {
+            if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+                if let Ok(folder) = vfs.get_mut_folder(parent) {
+                    let name = name.to_string_lossy().to_string();
+
+                    match node {
+                        Some(node) => folder.children.insert(name, node),
+                        None => folder.children.remove(&name),
+                    };
+                }
+            }
+
+            match tombstone {
+                Some(clock) => vfs.tombstones.insert(path_key(&path), clock),
+                None => vfs.tombstones.remove(&path_key(&path)),
+            };
+        }
+    }
+}
+
+/// Папку нельзя перенести в нее саму или в ее подпапку
+pub fn check_move(path: &Path, destination: &Path) -> Result<(), VFSError> {
+    if destination.starts_with(path) {
+        return Err(VFSError::PathError { message: String::from("Папка переносится в саму себя") });
+    }
+
+    Ok(())
+}
+
+fn stamp_clock(vfs: &VirtualFileSystem, node_path: &Path, clock: &mut VectorClock, device_id: &str) {
+    // Ревизия удаления папки покрывает и все, что в ней лежало
+    if let Ok(node) = vfs.get_node(node_path) {
+        merge_subtree_clock(node, clock);
+    }
+    if let Some(tombstone) = vfs.tombstones.get(&path_key(node_path)) {
+        clock.merge(tombstone);
+    }
+
+    clock.increment(device_id);
+}
+
+fn merge_subtree_clock(node: &FileSystemNode, clock: &mut VectorClock) {
+    clock.merge(&node.metadata().clock);
+
+    if let FileSystemNode::Folder(folder) = node {
+        for child in folder.children.values() {
+            merge_subtree_clock(child, clock);
+        }
+    }
+}
+
+/// Откуда была загружена VFS при старте
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VFSSource {
+    Snapshot,
+    Backup(PathBuf),
+    Empty,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadReport {
+    pub source: VFSSource,
+    /// Поврежденные файлы снимков, отложенные в сторону
+    pub corrupted: Vec<PathBuf>,
+    pub replayed_operations: usize,
+    pub skipped_operations: usize,
+}
+
+/// Хранение VFS на диске: снимок `vfs.json`, журнал мутаций и ротация резервных копий.
+///
+/// Каждая мутация сначала дописывается в журнал, а снимок периодически перезаписывается
+/// атомарно (запись во временный файл и переименование). При старте журнал проигрывается
+/// поверх снимка.
+#[derive(Debug)]
+pub struct VFSPersistence {
+    snapshot_path: PathBuf,
+    journal_path: PathBuf,
+    backups_count: usize,
+    journal_limit: usize,
+    journal_len: usize,
+}
+
+impl VFSPersistence {
+
+    /// `journal_limit` - кол-во операций в журнале, после которого перезаписывается снимок
+    pub fn new(snapshot_path: &Path, backups_count: usize, journal_limit: usize) -> Self {
+        Self {
+            snapshot_path: snapshot_path.to_path_buf(),
+            journal_path: snapshot_path.with_extension("journal"),
+            backups_count,
+            journal_limit,
+            journal_len: 0,
+        }
+    }
+
+    /// Кол-во операций в журнале с момента последнего снимка
+    pub fn journal_len(&self) -> usize {
+        self.journal_len
+    }
+
+    pub fn journal_limit(&self) -> usize {
+        self.journal_limit
+    }
+
+    /// Загрузка VFS со снимка, резервной копии или пустой VFS и проигрывание журнала
+    pub fn load(&mut self) -> io::Result<(VirtualFileSystem, LoadReport)> {
+
+        let mut report = LoadReport {
+            source: VFSSource::Empty,
+            corrupted: vec![],
+            replayed_operations: 0,
+            skipped_operations: 0,
+        };
+
+        let mut candidates = vec![(self.snapshot_path.clone(), VFSSource::Snapshot)];
+        candidates.extend(
+            (1..=self.backups_count)
+                .map(|index| self.backup_path(index))
+                .map(|path| (path.clone(), VFSSource::Backup(path)))
+        );
+
+        let mut vfs = None;
+
+        for (path, source) in candidates {
+            match read_snapshot(&path) {
+                Ok(Some(snapshot)) => {
+                    report.source = source;
+                    vfs = Some(snapshot);
+                    break;
+                },
+                Ok(None) => continue,
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    println!("Снимок VFS {} поврежден: {}", path.display(), e);
+                    report.corrupted.push(path);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut vfs = vfs.unwrap_or_else(|| VirtualFileSystem::new(FSOption::default()));
+
+        // Поврежденный основной снимок откладывается, чтобы не затереть его при следующей записи
+        if report.corrupted.contains(&self.snapshot_path) {
+            let time = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+
+            let mut corrupted_path = self.snapshot_path.clone().into_os_string();
+            corrupted_path.push(format!(".corrupted-{}", time));
+
+            fs::rename(&self.snapshot_path, &corrupted_path)?;
+        }
+
+        for operation in self.read_journal()? {
+            match operation.apply(&mut vfs) {
+                Ok(()) => report.replayed_operations += 1,
+                Err(e) => {
+                    println!("Операция журнала {:?} пропущена: {}", operation, e);
+                    report.skipped_operations += 1;
+                }
+            }
+        }
+
+        self.journal_len = report.replayed_operations + report.skipped_operations;
+
+        Ok((vfs, report))
+    }
+
+    /// Дописывание операции в журнал
+    pub fn record(&mut self, operation: &VFSOperation) -> io::Result<()> {
+
+        let mut line = serde_json::to_vec(operation)?;
+        line.push(b'\n');
+
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.journal_path)?;
+
+        journal.write_all(&line)?;
+        journal.sync_data()?;
+
+        self.journal_len += 1;
+
+        Ok(())
+    }
+
+    /// Атомарная запись снимка VFS, ротация резервных копий и очистка журнала
+    pub fn checkpoint(&mut self, vfs: &VirtualFileSystem) -> io::Result<()> {
+
+        let vfs_json = serde_json::to_vec(vfs)?;
+
+        let mut tmp_path = self.snapshot_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(&vfs_json)?;
+        tmp_file.sync_all()?;
+        drop(tmp_file);
+
+        self.rotate_backups()?;
+
+        fs::rename(&tmp_path, &self.snapshot_path)?;
+        sync_parent_dir(&self.snapshot_path)?;
+
+        // Журнал очищается только после того, как снимок надежно записан
+        File::create(&self.journal_path)?.sync_all()?;
+        self.journal_len = 0;
+
+        Ok(())
+    }
+
+    fn backup_path(&self, index: usize) -> PathBuf {
+        let mut path = self.snapshot_path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate_backups(&self) -> io::Result<()> {
+        if self.backups_count == 0 || !self.snapshot_path.is_file() {
+            return Ok(());
+        }
+
+        for index in (1..self.backups_count).rev() {
+            let from = self.backup_path(index);
+            if from.is_file() {
+                fs::rename(&from, self.backup_path(index + 1))?;
+            }
+        }
+
+        fs::copy(&self.snapshot_path, self.backup_path(1))?;
+
+        Ok(())
+    }
+
+    fn read_journal(&self) -> io::Result<Vec<VFSOperation>> {
+
+        let journal = match File::open(&self.journal_path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+
+        let mut operations = vec![];
+
+        for line in BufReader::new(journal).lines() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str::<VFSOperation>(&line) {
+                Ok(operation) => operations.push(operation),
+                // Оборванная последняя запись после сбоя во время дописывания
+                Err(e) => {
+                    println!("Запись журнала VFS не прочитана и будет отброшена: {}", e);
+                    break;
+                }
+            }
+        }
+
+        Ok(operations)
+    }
+}
+
+/// Чтение снимка. `Ok(None)` - снимка нет, `InvalidData` - снимок поврежден
+fn read_snapshot(path: &Path) -> io::Result<Option<VirtualFileSystem>> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    serde_json::from_reader::<_, VirtualFileSystem>(BufReader::new(file))
+        .map(Some)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    File::open(parent)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::virtual_file_system::{path_key, FSOption, FileSystemNode, VFSFolder, VectorClock, VirtualFileSystem};
+use crate::vfs_persistence::{VFSOperation, VFSPersistence};
+
+/// Хранилище VFS на диске
+pub trait VfsStore: Debug {
+    /// Загрузка VFS из хранилища
+    fn load(&mut self) -> io::Result<VirtualFileSystem>;
+
+    /// Сохранение одной мутации. `vfs` - состояние после применения операции
+    fn apply(&mut self, operation: &VFSOperation, vfs: &VirtualFileSystem) -> io::Result<()>;
+
+    /// Полная перезапись хранилища состоянием `vfs`
+    fn save(&mut self, vfs: &VirtualFileSystem) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsStoreKind {
+    Json,
+    Sled,
+}
+
+impl VfsStoreKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(VfsStoreKind::Json),
+            "sled" => Some(VfsStoreKind::Sled),
+            _ => None
+        }
+    }
+
+    /// Открытие хранилища по стандартному пути: `vfs.json` или `vfs.db`
+    pub fn open(self, dir: &Path) -> io::Result<Box<dyn VfsStore>> {
+        Ok(match self {
+            VfsStoreKind::Json =>
+                Box::new(VFSPersistence::new(&dir.join("vfs.json"), 3, 100)),
+            VfsStoreKind::Sled =>
+                Box::new(SledVfsStore::open(&dir.join("vfs.db"))?),
+        })
+    }
+
+    /// Выбор хранилища: база данных, если она уже создана, иначе json
+    pub fn detect(dir: &Path) -> Self {
+        if dir.join("vfs.db").is_dir() {
+            VfsStoreKind::Sled
+        } else {
+            VfsStoreKind::Json
+        }
+    }
+
+    /// Отключение хранилища после переноса VFS в другое, чтобы `detect` его больше не выбирал.
+    /// База переименовывается в `vfs.db.old`, json выбирается только без базы и остается на месте
+    pub fn retire(self, dir: &Path) -> io::Result<()> {
+        match self {
+            VfsStoreKind::Json => Ok(()),
+            VfsStoreKind::Sled => {
+                let retired_path = dir.join("vfs.db.old");
+                if retired_path.exists() {
+                    std::fs::remove_dir_all(&retired_path)?;
+                }
+
+                std::fs::rename(dir.join("vfs.db"), retired_path)
+            }
+        }
+    }
+}
+
+/// Копирование VFS из одного хранилища в другое
+pub fn migrate(from: &mut dyn VfsStore, to: &mut dyn VfsStore) -> io::Result<VirtualFileSystem> {
+    let vfs = from.load()?;
+    to.save(&vfs)?;
+    Ok(vfs)
+}
+
+impl VfsStore for VFSPersistence {
+    fn load(&mut self) -> io::Result<VirtualFileSystem> {
+        let (vfs, report) = VFSPersistence::load(self)?;
+
+        if report.source != crate::vfs_persistence::VFSSource::Snapshot
+            || report.replayed_operations > 0
+        {
+            println!("Восстановление VFS: {:?}", report);
+        }
+
+        Ok(vfs)
+    }
+
+    fn apply(&mut self, operation: &VFSOperation, vfs: &VirtualFileSystem) -> io::Result<()> {
+        self.record(operation)?;
+
+        if self.journal_len() >= self.journal_limit() {
+            self.checkpoint(vfs)?;
+        }
+
+        Ok(())
+    }
+
+    fn save(&mut self, vfs: &VirtualFileSystem) -> io::Result<()> {
+        self.checkpoint(vfs)
+    }
+}
+
+const OPTIONS_KEY: &[u8] = b"@options";
+const SEEN_KEY: &[u8] = b"@seen";
+const TOMBSTONE_PREFIX: &str = "@tombstone:";
+const ROOT_PATH: &str = "fs://";
+
+/// Хранение VFS во встроенной базе sled.
+///
+/// Каждый узел лежит под ключом своего виртуального пути (`fs://a/b`), папки хранятся
+/// без детей. Надгробия удаленных узлов хранятся под ключами `@tombstone:fs://a/b`,
+/// часы устройств из `seen` - под ключом `@seen`.
+/// Мутации меняют только затронутые ключи.
+#[derive(Debug)]
+pub struct SledVfsStore {
+    db: sled::Db,
+}
+
+impl SledVfsStore {
+
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self { db: sled::open(path)? })
+    }
+
+    /// Получение узла по пути без загрузки всего дерева (папки возвращаются без детей)
+    pub fn get(&self, path: &Path) -> io::Result<Option<FileSystemNode>> {
+        self.db
+            .get(path_key(path).as_bytes())?
+            .map(|value| serde_json::from_slice(&value).map_err(io::Error::from))
+            .transpose()
+    }
+
+    fn insert_node(&self, batch: &mut sled::Batch, path: &Path, node: &FileSystemNode) -> io::Result<()> {
+        match node {
+            FileSystemNode::File(_) => {
+                batch.insert(path_key(path).as_bytes(), serde_json::to_vec(node)?);
+            },
+            FileSystemNode::Folder(folder) => {
+                let folder_without_children = FileSystemNode::Folder(VFSFolder {
+                    name: folder.name.clone(),
+                    metadata: folder.metadata.clone(),
+                    children: HashMap::new(),
+                });
+                batch.insert(path_key(path).as_bytes(), serde_json::to_vec(&folder_without_children)?);
+
+                for (name, child) in &folder.children {
+                    self.insert_node(batch, &path.join(name), child)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn remove_node(&self, batch: &mut sled::Batch, path: &Path) -> io::Result<()> {
+        let key = path_key(path);
+        batch.remove(key.as_bytes());
+
+        for entry in self.db.scan_prefix(format!("{}/", key).as_bytes()) {
+            batch.remove(entry?.0);
+        }
+
+        Ok(())
+    }
+}
+
+impl VfsStore for SledVfsStore {
+    fn load(&mut self) -> io::Result<VirtualFileSystem> {
+
+        let options = match self.db.get(OPTIONS_KEY)? {
+            Some(value) => serde_json::from_slice::<FSOption>(&value)?,
+            None => FSOption::default()
+        };
+
+        let mut vfs = VirtualFileSystem::new(options);
+
+        if let Some(value) = self.db.get(SEEN_KEY)? {
+            vfs.seen = serde_json::from_slice(&value)?;
+        }
+
+        // Ключи отсортированы, поэтому родительская папка всегда читается раньше детей
+        for entry in self.db.scan_prefix(ROOT_PATH.as_bytes()) {
+            let (key, value) = entry?;
+
+            let path = PathBuf::from(String::from_utf8_lossy(&key).into_owned());
+            let parent = path.parent().unwrap_or(Path::new(ROOT_PATH));
+
+            let node = serde_json::from_slice::<FileSystemNode>(&value)?;
+
+            let folder = vfs
+                .get_mut_folder(parent)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+            let name = path
+                .file_name()
+                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Ключ без имени узла"))?
+                .to_string_lossy()
+                .to_string();
+
+            folder.children.insert(name, node);
+        }
+
+        for entry in self.db.scan_prefix(TOMBSTONE_PREFIX.as_bytes()) {
+            let (key, value) = entry?;
+
+            let path = String::from_utf8_lossy(&key[TOMBSTONE_PREFIX.len()..]).into_owned();
+            vfs.tombstones.insert(path, serde_json::from_slice::<VectorClock>(&value)?);
+        }
+
+        Ok(vfs)
+    }
+
+    fn apply(&mut self, operation: &VFSOperation, _vfs: &VirtualFileSystem) -> io::Result<()> {
+        let mut batch = sled::Batch::default();
+
+        match operation {
+            VFSOperation::AddFile { path, file } => {
+                let file_path = path.join(&file.name);
+                self.insert_node(&mut batch, &file_path, &FileSystemNode::File(file.clone()))?;
+                batch.remove(tombstone_key(&file_path).as_bytes());
+            },
+
+            VFSOperation::AddFolder { path, folder } => {
+                let folder_path = path.join(&folder.name);
+                self.insert_node(&mut batch, &folder_path, &FileSystemNode::Folder(folder.clone()))?;
+                batch.remove(tombstone_key(&folder_path).as_bytes());
+            },
+
+            VFSOperation::RemoveNode { path, clock } => {
+                self.remove_node(&mut batch, path)?;
+
+                if !clock.is_empty() {
+                    batch.insert(tombstone_key(path).as_bytes(), serde_json::to_vec(clock)?);
+                }
+            },
+
+            VFSOperation::MoveNode { path, destination, node, clock } => {
+                let node_path = destination.join(node.name());
+
+                self.remove_node(&mut batch, path)?;
+                self.insert_node(&mut batch, &node_path, node)?;
+                batch.remove(tombstone_key(&node_path).as_bytes());
+
+                if !clock.is_empty() {
+                    batch.insert(tombstone_key(path).as_bytes(), serde_json::to_vec(clock)?);
+                }
+            }
+        }
+
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    fn save(&mut self, vfs: &VirtualFileSystem) -> io::Result<()> {
+        let mut batch = sled::Batch::default();
+
+        for entry in self.db.iter() {
+            batch.remove(entry?.0);
+        }
+
+        batch.insert(OPTIONS_KEY, serde_json::to_vec(&vfs.options)?);
+        batch.insert(SEEN_KEY, serde_json::to_vec(&vfs.seen)?);
+
+        for (path, clock) in &vfs.tombstones {
+            batch.insert(format!("{}{}", TOMBSTONE_PREFIX, path).as_bytes(), serde_json::to_vec(clock)?);
+        }
+
+        if let Ok(root) = vfs.get_folder(Path::new(ROOT_PATH)) {
+            for (name, node) in &root.children {
+                self.insert_node(&mut batch, &Path::new(ROOT_PATH).join(name), node)?;
+            }
+        }
+
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+
+        Ok(())
+    }
+}
+
+fn tombstone_key(path: &Path) -> String {
+    format!("{}{}", TOMBSTONE_PREFIX, path_key(path))
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fmt::{self, Display, Formatter, write};
 use std::path::{Path, PathBuf};
@@ -10,11 +10,164 @@ use serde_json::Error;
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct FSOption {
     version: i64,
-    owner: String
+    owner: String,
+    /// Версия последнего влитого снимка VFS каждого устройства
+    #[serde(default)]
+    pulled_versions: HashMap<String, u64>,
 }
 
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct Metadata;
+impl FSOption {
+    /// Версия последнего снимка VFS, синхронизированного с облаком
+    pub fn version(&self) -> i64 {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: i64) {
+        self.version = version;
+    }
+
+    pub fn pulled_versions(&self) -> &HashMap<String, u64> {
+        &self.pulled_versions
+    }
+
+    pub fn set_pulled_version(&mut self, device_id: &str, version: u64) {
+        self.pulled_versions.insert(device_id.to_owned(), version);
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Ревизия узла: сколько изменений внесло каждое устройство
+    #[serde(default)]
+    pub clock: VectorClock,
+    /// Размеры и хеши метафайла и частей файла на момент загрузки
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub checksums: BTreeMap<String, Checksum>,
+    /// Размер и хеш исходного файла, по нему находятся уже загруженные копии
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<Checksum>,
+    /// Время изменения исходного файла, секунды unix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
+    /// Права доступа исходного файла (unix mode)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<u32>,
+    /// Цель символической ссылки, если узел сохранен как ссылка, а не как содержимое
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<PathBuf>,
+}
+
+impl Metadata {
+    /// Запись времени изменения и прав доступа локального файла
+    pub fn record_attributes(&mut self, attributes: &std::fs::Metadata) {
+        self.modified = modified_secs(attributes);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            self.permissions = Some(attributes.permissions().mode());
+        }
+    }
+}
+
+/// Размер и md5 содержимого файла, хранящегося в облаке
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checksum {
+    pub size: u64,
+    pub md5: String,
+}
+
+impl Checksum {
+    pub fn of_file(path: &Path) -> std::io::Result<Self> {
+        use std::io::Read;
+
+        let mut file = std::fs::File::open(path)?;
+        let mut context = md5::Context::new();
+        let mut buffer = vec![0_u8; 1 << 20];
+        let mut size = 0;
+
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            context.consume(&buffer[..read]);
+            size += read as u64;
+        }
+
+        Ok(Self {
+            size,
+            md5: format!("{:x}", context.compute()),
+        })
+    }
+}
+
+/// Время изменения файла в секундах unix time
+pub fn modified_secs(attributes: &std::fs::Metadata) -> Option<u64> {
+    attributes
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|modified| modified.as_secs())
+}
+
+/// Старые снимки VFS хранят метаданные как `null`
+fn metadata_or_default<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Metadata, D::Error> {
+    Ok(Option::<Metadata>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockOrdering {
+    Equal,
+    Before,
+    After,
+    Concurrent,
+}
+
+/// Векторные часы: счетчик изменений для каждого устройства
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VectorClock(pub BTreeMap<String, u64>);
+
+impl VectorClock {
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Учет нового изменения, сделанного устройством `device_id`
+    pub fn increment(&mut self, device_id: &str) {
+        *self.0.entry(device_id.to_owned()).or_insert(0) += 1;
+    }
+
+    /// Объединение часов: максимум счетчиков каждого устройства
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (device_id, counter) in &other.0 {
+            let current = self.0.entry(device_id.clone()).or_insert(0);
+            *current = (*current).max(*counter);
+        }
+    }
+
+    /// Сравнение часов: `Before` - `self` предшествует `other`
+    pub fn compare(&self, other: &VectorClock) -> ClockOrdering {
+        let mut less = false;
+        let mut greater = false;
+
+        for device_id in self.0.keys().chain(other.0.keys()) {
+            let self_counter = self.0.get(device_id).copied().unwrap_or(0);
+            let other_counter = other.0.get(device_id).copied().unwrap_or(0);
+
+            less |= self_counter < other_counter;
+            greater |= self_counter > other_counter;
+        }
+
+        match (less, greater) {
+            (false, false) => ClockOrdering::Equal,
+            (true, false) => ClockOrdering::Before,
+            (false, true) => ClockOrdering::After,
+            (true, true) => ClockOrdering::Concurrent,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum FileSystemNode {
@@ -22,18 +175,43 @@ pub enum FileSystemNode {
     Folder(VFSFolder)
 }
 
+impl FileSystemNode {
+    pub fn name(&self) -> &str {
+        match self {
+            FileSystemNode::File(file) => &file.name,
+            FileSystemNode::Folder(folder) => &folder.name,
+        }
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        match self {
+            FileSystemNode::File(file) => &file.metadata,
+            FileSystemNode::Folder(folder) => &folder.metadata,
+        }
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut Metadata {
+        match self {
+            FileSystemNode::File(file) => &mut file.metadata,
+            FileSystemNode::Folder(folder) => &mut folder.metadata,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VFSFile {
     pub name: String,
     pub extension: String,
     pub build_metafile: String,
     pub parts_name: Vec<String>,
+    #[serde(default, deserialize_with = "metadata_or_default")]
     pub metadata : Metadata
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VFSFolder {
     pub name: String,
+    #[serde(default, deserialize_with = "metadata_or_default")]
     pub metadata : Metadata,
     pub children: HashMap<String, FileSystemNode>
 }
@@ -42,6 +220,13 @@ pub struct VFSFolder {
 pub struct VirtualFileSystem {
     pub dirs: HashMap<String, FileSystemNode>,
     pub options: FSOption,
+    /// Ревизии удаленных узлов по их пути, нужны для слияния VFS с разных устройств
+    #[serde(default)]
+    pub tombstones: HashMap<String, VectorClock>,
+    /// Что видело каждое устройство при последнем слиянии или выгрузке снимка:
+    /// объединение часов его VFS. Надгробия, которые видели все устройства, удаляются
+    #[serde(default)]
+    pub seen: HashMap<String, VectorClock>,
 }
 
 impl VirtualFileSystem {
@@ -59,6 +244,8 @@ impl VirtualFileSystem {
                 )
             ]),
             options,
+            tombstones: HashMap::new(),
+            seen: HashMap::new(),
         }
     }
 
@@ -169,6 +356,74 @@ impl VirtualFileSystem {
         Ok(())
     }
 
+    /// Получение узла по виртуальному пути
+    pub fn get_node(&self, path: &Path) -> Result<&FileSystemNode, VFSError> {
+        self.get_fs_node(path)
+    }
+
+    /// Получение всех узлов VFS вместе с их виртуальными путями (корень не включается)
+    pub fn nodes(&self) -> Vec<(PathBuf, &FileSystemNode)> {
+
+        fn walk<'a>(
+            folder: &'a VFSFolder,
+            path: &Path,
+            output: &mut Vec<(PathBuf, &'a FileSystemNode)>
+        ) {
+            for (name, node) in &folder.children {
+                let node_path = path.join(name);
+
+                if let FileSystemNode::Folder(child_folder) = node {
+                    walk(child_folder, &node_path, output);
+                }
+
+                output.push((node_path, node));
+            }
+        }
+
+        let mut output = vec![];
+
+        if let Some(FileSystemNode::Folder(root)) = self.dirs.get("fs:") {
+            walk(root, Path::new("fs://"), &mut output);
+        }
+
+        output
+    }
+
+    /// Запись того, что видело устройство `device_id`: часы всех узлов и надгробий VFS
+    pub fn record_seen(&mut self, device_id: &str) {
+        let mut seen = VectorClock::default();
+
+        for (_, node) in self.nodes() {
+            seen.merge(&node.metadata().clock);
+        }
+        for clock in self.tombstones.values() {
+            seen.merge(clock);
+        }
+
+        self.seen.entry(device_id.to_owned()).or_default().merge(&seen);
+    }
+
+    /// Удаление надгробий, которые видели все известные устройства.
+    /// Устройство известно, если оно есть в `seen` или в часах узлов и надгробий
+    pub fn prune_tombstones(&mut self) {
+        let mut devices = self.seen.keys().cloned().collect::<HashSet<_>>();
+
+        for (_, node) in self.nodes() {
+            devices.extend(node.metadata().clock.0.keys().cloned());
+        }
+        for clock in self.tombstones.values() {
+            devices.extend(clock.0.keys().cloned());
+        }
+
+        let seen = &self.seen;
+        self.tombstones.retain(|_, tombstone| {
+            !devices.iter().all(|device_id| matches!(
+                seen.get(device_id).map(|clock| clock.compare(tombstone)),
+                Some(ClockOrdering::Equal | ClockOrdering::After)
+            ))
+        });
+    }
+
     /// Получение мутабельного узла виртуального пути
     fn get_mut_fs_node(&mut self, path: &Path) -> Result<&mut FileSystemNode, VFSError> {
 
@@ -255,6 +510,18 @@ impl VirtualFileSystem {
     }
 }
 
+/// Строковый ключ виртуального пути в виде `fs://a/b`
+pub fn path_key(path: &Path) -> String {
+    let relative = path
+        .iter()
+        .skip(1)
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    format!("fs://{}", relative)
+}
+
 impl Display for VirtualFileSystem {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "{}", serde_json::to_string(&self).unwrap())
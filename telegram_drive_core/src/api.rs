@@ -0,0 +1,331 @@
+//! Типизированные методы и объекты TDLib, которые использует приложение.
+//!
+//! Имена типов и полей повторяют td_api.tl, `@type` объектов хранится в serde теге.
+//! Описаны только используемые поля, остальные поля ответов пропускаются.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::error::TDError;
+
+/// Метод TDLib и тип его ответа
+pub trait Function: Serialize {
+    type Response: DeserializeOwned;
+}
+
+macro_rules! function {
+    ($function:ident => $response:ty) => {
+        impl Function for $function {
+            type Response = $response;
+        }
+    };
+}
+
+// Методы
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "@type", rename = "getMe")]
+pub struct GetMe {}
+function!(GetMe => User);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "@type", rename = "createNewSupergroupChat")]
+pub struct CreateNewSupergroupChat {
+    pub title: String,
+}
+function!(CreateNewSupergroupChat => Chat);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "@type", rename = "getChat")]
+pub struct GetChat {
+    pub chat_id: i64,
+}
+function!(GetChat => Chat);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "@type", rename = "searchChats")]
+pub struct SearchChats {
+    pub query: String,
+    pub limit: i32,
+}
+function!(SearchChats => Chats);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "@type", rename = "getChatHistory")]
+pub struct GetChatHistory {
+    pub chat_id: i64,
+    /// 0 - с последнего сообщения
+    pub from_message_id: i64,
+    pub offset: i32,
+    pub limit: i32,
+    pub only_local: bool,
+}
+function!(GetChatHistory => Messages);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "@type", rename = "getMessage")]
+pub struct GetMessage {
+    pub chat_id: i64,
+    pub message_id: i64,
+}
+function!(GetMessage => Message);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "@type", rename = "sendMessage")]
+pub struct SendMessage {
+    pub chat_id: i64,
+    pub input_message_content: InputMessageContent,
+}
+function!(SendMessage => Message);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "@type", rename = "deleteMessages")]
+pub struct DeleteMessages {
+    pub chat_id: i64,
+    pub message_ids: Vec<i64>,
+    pub revoke: bool,
+}
+function!(DeleteMessages => Ok);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "@type", rename = "pinChatMessage")]
+pub struct PinChatMessage {
+    pub chat_id: i64,
+    pub message_id: i64,
+    pub disable_notification: bool,
+    pub only_for_self: bool,
+}
+function!(PinChatMessage => Ok);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "@type", rename = "getFile")]
+pub struct GetFile {
+    pub file_id: i64,
+}
+function!(GetFile => File);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "@type", rename = "downloadFile")]
+pub struct DownloadFile {
+    pub file_id: i64,
+    /// 1..32, файлы с большим приоритетом скачиваются раньше
+    pub priority: i32,
+    pub offset: u64,
+    /// 0 - до конца файла
+    pub limit: u64,
+    /// Ответить только после окончания скачивания
+    pub synchronous: bool,
+}
+function!(DownloadFile => File);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "@type", rename = "cancelDownloadFile")]
+pub struct CancelDownloadFile {
+    pub file_id: i64,
+    pub only_if_pending: bool,
+}
+function!(CancelDownloadFile => Ok);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "@type", rename = "deleteFile")]
+pub struct DeleteFile {
+    pub file_id: i64,
+}
+function!(DeleteFile => Ok);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "@type")]
+pub enum InputMessageContent {
+    #[serde(rename = "inputMessageDocument")]
+    InputMessageDocument { document: InputFile },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "@type")]
+pub enum InputFile {
+    #[serde(rename = "inputFileLocal")]
+    InputFileLocal { path: String },
+}
+
+// Объекты
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "@type", rename = "ok")]
+pub struct Ok {}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "@type", rename = "user", default)]
+pub struct User {
+    pub id: i64,
+    pub first_name: String,
+    pub last_name: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "@type", rename = "chat", default)]
+pub struct Chat {
+    pub id: i64,
+    pub title: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "@type", rename = "chats", default)]
+pub struct Chats {
+    pub total_count: i32,
+    pub chat_ids: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "@type", rename = "messages", default)]
+pub struct Messages {
+    pub total_count: i32,
+    /// Недоступные сообщения TDLib присылает как null
+    pub messages: Vec<Option<Message>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "@type", rename = "message", default)]
+pub struct Message {
+    pub id: i64,
+    pub chat_id: i64,
+    /// Время отправки (unix time)
+    pub date: i64,
+    pub content: MessageContent,
+    /// `None` - сообщение уже отправлено
+    pub sending_state: Option<MessageSendingState>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "@type")]
+pub enum MessageSendingState {
+    #[serde(rename = "messageSendingStatePending")]
+    Pending {},
+    #[serde(rename = "messageSendingStateFailed")]
+    Failed {},
+}
+
+impl Message {
+    /// Документ сообщения, у сообщений другого типа документа нет
+    pub fn document(&self) -> Option<&Document> {
+        match &self.content {
+            MessageContent::MessageDocument { document } => Some(document),
+            MessageContent::Unsupported => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "@type")]
+pub enum MessageContent {
+    #[serde(rename = "messageDocument")]
+    MessageDocument { document: Document },
+    /// Сообщения других типов приложение не использует
+    #[default]
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "@type", rename = "document", default)]
+pub struct Document {
+    pub file_name: String,
+    pub mime_type: String,
+    pub document: File,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "@type", rename = "file", default)]
+pub struct File {
+    pub id: i64,
+    pub size: u64,
+    pub expected_size: u64,
+    pub local: LocalFile,
+    pub remote: RemoteFile,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "@type", rename = "localFile", default)]
+pub struct LocalFile {
+    pub path: String,
+    pub is_downloading_active: bool,
+    pub is_downloading_completed: bool,
+    /// Скачанные без пропусков байты с начала файла
+    pub downloaded_prefix_size: u64,
+    pub downloaded_size: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "@type", rename = "remoteFile", default)]
+pub struct RemoteFile {
+    pub id: String,
+    pub unique_id: String,
+    pub is_uploading_active: bool,
+    pub is_uploading_completed: bool,
+    pub uploaded_size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "@type")]
+pub enum AuthorizationState {
+    #[serde(rename = "authorizationStateWaitTdlibParameters")]
+    WaitTdlibParameters,
+    #[serde(rename = "authorizationStateWaitPhoneNumber")]
+    WaitPhoneNumber,
+    #[serde(rename = "authorizationStateWaitCode")]
+    WaitCode,
+    #[serde(rename = "authorizationStateWaitPassword")]
+    WaitPassword,
+    #[serde(rename = "authorizationStateReady")]
+    Ready,
+    #[serde(rename = "authorizationStateLoggingOut")]
+    LoggingOut,
+    #[serde(rename = "authorizationStateClosing")]
+    Closing,
+    #[serde(rename = "authorizationStateClosed")]
+    Closed,
+    /// Регистрация, подтверждение с другого устройства и другие состояния
+    #[serde(other)]
+    Other,
+}
+
+// Обновления
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "@type")]
+pub enum Update {
+    #[serde(rename = "updateAuthorizationState")]
+    AuthorizationState { authorization_state: AuthorizationState },
+    #[serde(rename = "updateFile")]
+    File { file: File },
+    #[serde(rename = "updateMessageSendSucceeded")]
+    MessageSendSucceeded { message: Message, old_message_id: i64 },
+    #[serde(rename = "updateMessageSendFailed")]
+    MessageSendFailed {
+        message: Message,
+        old_message_id: i64,
+        #[serde(default)]
+        error: Option<TDError>,
+        /// Старые версии TDLib присылают ошибку в полях error_code и error_message
+        #[serde(default)]
+        error_code: i64,
+        #[serde(default)]
+        error_message: String,
+    },
+    /// Обновления других типов приложение не разбирает
+    #[serde(other)]
+    Unsupported,
+}
+
+impl Update {
+    /// Ошибка отправки сообщения из `updateMessageSendFailed`
+    pub fn send_error(&self) -> Option<TDError> {
+        match self {
+            Update::MessageSendFailed { error: Some(error), .. } => Some(error.clone()),
+            Update::MessageSendFailed { error_code, error_message, .. } => Some(TDError {
+                code: *error_code,
+                message: error_message.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
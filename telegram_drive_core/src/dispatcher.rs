@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+use tokio::sync::{broadcast, oneshot};
+
+use crate::tdjson::td_receive;
+
+/// Таймаут одного вызова td_receive в потоке приема, в секундах
+const RECEIVE_TIMEOUT: f64 = 1.0;
+/// Сколько обновлений хранит канал для подписчиков, не успевающих их читать
+const UPDATES_CAPACITY: usize = 1024;
+
+#[derive(Debug)]
+struct PendingRequest {
+    sender: oneshot::Sender<Value>,
+    /// После этого времени ожидающий запрос получает `NoResponse`
+    deadline: Instant,
+}
+
+/// Разбор всего, что присылает TDLib.
+///
+/// td_receive вызывает только отдельный поток приема. Ответы на запросы с `@extra`
+/// передаются ожидающим их запросам, все остальное публикуется как обновления.
+#[derive(Debug)]
+pub(crate) struct Dispatcher {
+    /// Запросы, ожидающие ответа, по `@extra`
+    pending: Mutex<HashMap<u64, PendingRequest>>,
+    updates: broadcast::Sender<Value>,
+    stopped: AtomicBool,
+}
+
+impl Dispatcher {
+
+    /// Запуск потока приема
+    pub fn start() -> Arc<Self> {
+        let dispatcher = Arc::new(Dispatcher {
+            pending: Mutex::new(HashMap::new()),
+            updates: broadcast::channel(UPDATES_CAPACITY).0,
+            stopped: AtomicBool::new(false),
+        });
+
+        let receive_dispatcher = dispatcher.clone();
+        thread::Builder::new()
+            .name("tdlib-receive".to_owned())
+            .spawn(move || receive_dispatcher.run())
+            .expect("Не удалось запустить поток приема TDLib");
+
+        dispatcher
+    }
+
+    /// Остановка потока приема, он завершится после текущего вызова td_receive
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// Ожидание ответа на запрос с `@extra`. Без ответа за `timeout` канал закрывается
+    pub fn register(&self, extra: u64, timeout: Duration) -> oneshot::Receiver<Value> {
+        let (sender, response) = oneshot::channel();
+
+        self.pending.lock().unwrap().insert(extra, PendingRequest {
+            sender,
+            deadline: Instant::now() + timeout,
+        });
+
+        response
+    }
+
+    /// Подписка на обновления с `@type` из `types`, пустой список - все обновления
+    pub fn subscribe(&self, types: &[&str]) -> UpdateReceiver {
+        UpdateReceiver {
+            receiver: self.updates.subscribe(),
+            types: types.iter().map(|update_type| update_type.to_string()).collect(),
+        }
+    }
+
+    fn run(&self) {
+        while !self.stopped.load(Ordering::Relaxed) {
+            if let Some(response) = receive(RECEIVE_TIMEOUT) {
+                match serde_json::from_str::<Value>(&response) {
+                    Ok(json) => self.dispatch(json),
+                    Err(e) => println!("TDLib прислал невалидный json: {}", e),
+                }
+            }
+
+            self.expire_requests();
+        }
+    }
+
+    fn dispatch(&self, json: Value) {
+        if let Some(extra) = json["@extra"].as_u64() {
+            if let Some(request) = self.pending.lock().unwrap().remove(&extra) {
+                let _ = request.sender.send(json);
+                return;
+            }
+        }
+
+        let _ = self.updates.send(json);
+    }
+
+    fn expire_requests(&self) {
+        let now = Instant::now();
+        self.pending.lock().unwrap().retain(|_, request| request.deadline > now);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// Подписчик не успевал читать обновления, указанное кол-во пропущено
+    Lagged(u64),
+    /// Поток приема остановлен
+    Closed,
+}
+
+/// Подписка на обновления TDLib нужных типов
+#[derive(Debug)]
+pub struct UpdateReceiver {
+    receiver: broadcast::Receiver<Value>,
+    types: Vec<String>,
+}
+
+impl UpdateReceiver {
+
+    /// Следующее подходящее обновление. После `RecvError::Lagged` подписка продолжает
+    /// работать, но пропущенные обновления не вернуть: состояние нужно запросить заново
+    pub async fn recv(&mut self) -> Result<Value, RecvError> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(update) if self.is_subscribed(&update) => return Ok(update),
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    println!("Подписчик не успевал читать обновления TDLib, пропущено: {}", skipped);
+                    return Err(RecvError::Lagged(skipped));
+                }
+                Err(broadcast::error::RecvError::Closed) => return Err(RecvError::Closed),
+            }
+        }
+    }
+
+    fn is_subscribed(&self, update: &Value) -> bool {
+        self.types.is_empty() || self.types.iter().any(|update_type| update["@type"] == update_type.as_str())
+    }
+}
+
+fn receive(timeout: f64) -> Option<String> {
+    unsafe {
+        td_receive(timeout)
+            .as_ref()
+            .map(|chars| CStr::from_ptr(chars).to_string_lossy().into_owned())
+    }
+}
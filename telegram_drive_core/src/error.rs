@@ -1,12 +1,121 @@
+use std::fmt::{self, Display, Formatter};
 use std::io;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 #[derive(Debug)]
 pub enum TDAppError {
     IOError(io::Error),
+    /// TDLib ответил объектом `error`
+    TDLib(TDError),
+    /// TDLib перестал присылать обновления до завершения запроса
+    NoResponse,
+    /// Неповторяемый запрос остался без ответа и мог быть выполнен, поэтому не повторяется
+    Unconfirmed,
+    Cancelled,
+    /// Повторяемая ошибка не прошла за `attempts` попыток
+    RetriesExhausted { attempts: u32, last: Box<TDAppError> },
+    /// Запрос или ответ не совпадает с описанием метода в `api`
+    InvalidJson(serde_json::Error),
+}
+
+impl From<serde_json::Error> for TDAppError {
+    fn from(value: serde_json::Error) -> Self {
+        TDAppError::InvalidJson(value)
+    }
 }
 
 impl From<io::Error> for TDAppError {
     fn from(value: io::Error) -> Self {
         TDAppError::IOError(value)
     }
-}
\ No newline at end of file
+}
+
+impl From<TDError> for TDAppError {
+    fn from(value: TDError) -> Self {
+        TDAppError::TDLib(value)
+    }
+}
+
+impl TDAppError {
+    pub fn kind(&self) -> TDErrorKind {
+        match self {
+            TDAppError::TDLib(error) => error.kind(),
+            TDAppError::NoResponse => TDErrorKind::Retryable,
+            _ => TDErrorKind::Fatal,
+        }
+    }
+}
+
+/// Объект `error` TDLib
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TDError {
+    pub code: i64,
+    pub message: String,
+}
+
+/// Можно ли повторить запрос, завершившийся ошибкой
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TDErrorKind {
+    /// Сервер ограничил частоту запросов, повтор не раньше указанного времени
+    FloodWait(Duration),
+    /// Сетевая ошибка или ошибка сервера
+    Retryable,
+    Fatal,
+}
+
+impl TDError {
+
+    /// Ошибка из объекта `error` или из поля `error` обновления, например `updateMessageSendFailed`
+    pub fn from_json(json: &Value) -> Self {
+        // Старые версии TDLib присылают ошибку отправки в полях error_code и error_message
+        match json["@type"].as_str() {
+            Some("error") => TDError {
+                code: json["code"].as_i64().unwrap_or_default(),
+                message: json["message"].as_str().unwrap_or_default().to_owned(),
+            },
+            _ if json["error"].is_object() => TDError::from_json(&json["error"]),
+            _ => TDError {
+                code: json["error_code"].as_i64().unwrap_or_default(),
+                message: json["error_message"].as_str().unwrap_or_default().to_owned(),
+            },
+        }
+    }
+
+    pub fn kind(&self) -> TDErrorKind {
+        if let Some(wait) = self.flood_wait() {
+            return TDErrorKind::FloodWait(wait);
+        }
+
+        const RETRYABLE_MESSAGES: [&str; 5] = ["TIMEOUT", "NETWORK", "Request aborted", "RPC_CALL_FAIL", "Connection"];
+
+        if self.code >= 500 || RETRYABLE_MESSAGES.iter().any(|message| self.message.contains(message)) {
+            TDErrorKind::Retryable
+        } else {
+            TDErrorKind::Fatal
+        }
+    }
+
+    /// Время ожидания из `FLOOD_WAIT_X` или `Too Many Requests: retry after X`
+    fn flood_wait(&self) -> Option<Duration> {
+        let seconds = self.message
+            .strip_prefix("FLOOD_WAIT_")
+            .or_else(|| self.message.split("retry after ").nth(1))
+            .and_then(|seconds| seconds.trim().parse().ok());
+
+        match seconds {
+            Some(seconds) => Some(Duration::from_secs(seconds)),
+            None if self.code == 429 => Some(Duration::ZERO),
+            None => None,
+        }
+    }
+}
+
+impl Display for TDError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
@@ -14,7 +14,7 @@ use std::path::{Iter, Path, PathBuf};
 use std::pin::Pin;
 use std::sync::{Arc, mpsc, Mutex};
 use std::task::{Context, Poll};
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
 use serde_json::{Value, json};
 
@@ -33,19 +33,48 @@ use tokio::{
 
 mod tdjson;
 mod authentication;
+mod dispatcher;
+pub mod api;
 pub mod error;
+pub mod retry;
 
 use authentication as auth;
 
 use tdjson::*;
 use error::*;
-
+use dispatcher::Dispatcher;
+use api::{AuthorizationState, Function, Update};
+
+pub use dispatcher::{RecvError, UpdateReceiver};
+
+/// Сколько ждать ответа на запрос
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Сколько ждать обновлений передачи файла, после этого TDLib считается зависшим
+const UPDATE_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Шаг, с которым при ожидании обновлений передачи проверяется отмена
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Событие подписки на обновления передачи файла
+enum TransferEvent {
+    Update(Box<Update>),
+    /// Часть обновлений потеряна, состояние передачи нужно запросить заново
+    Lagged,
+}
 
 #[derive(Debug)]
 pub struct TDApp {
     client_id: i32,
+    /// `@extra` последнего запроса
     current_query_id: AtomicU64,
     error_log_file: AsyncMutex<AsyncFile>,
+    /// Поток приема ответов и обновлений TDLib
+    dispatcher: Arc<Dispatcher>,
+}
+
+impl Drop for TDApp {
+    fn drop(&mut self) {
+        self.dispatcher.stop();
+    }
 }
 
 impl TDApp {
@@ -64,7 +93,8 @@ impl TDApp {
             current_query_id: AtomicU64::new(0),
             error_log_file: AsyncMutex::new(
                 AsyncFile::create(format!("logs/error_{}.log", time)).await.unwrap()
-            )
+            ),
+            dispatcher: Dispatcher::start(),
         }
     }
 
@@ -84,22 +114,6 @@ impl TDApp {
         }
     }
 
-    pub async fn receive(&self, timeout: f64) -> Option<String> {
-        self.sync_receive(timeout)
-    }
-
-    pub fn sync_receive(&self, timeout: f64) -> Option<String> {
-        unsafe {
-            let response = td_receive(timeout);
-
-            return response.as_ref().map(
-                |chars|
-                    CStr::from_ptr(chars).to_string_lossy().into_owned()
-            )
-
-        }
-    }
-
     pub async fn send_query(&self, request: &str) -> Result<(), std::ffi::NulError> {
         unsafe {
             td_send(
@@ -108,31 +122,59 @@ impl TDApp {
             );
         }
 
-        self.current_query_id.fetch_add(1, Ordering::Relaxed);
-
         Ok(())
     }
 
-    fn next_update_json(&self) -> Option<Value> {
-        if let Some(response) = self.sync_receive(1.0) {
+    /// Отправка запроса с `@extra` и ожидание ответа именно на него, поэтому запросы
+    /// можно выполнять одновременно. Ответ `error` возвращается как `TDAppError::TDLib`
+    pub async fn request(&self, mut query: Value) -> Result<Value, TDAppError> {
+        let extra = self.current_query_id.fetch_add(1, Ordering::Relaxed) + 1;
+        query["@extra"] = json!(extra);
 
-            let json = serde_json::from_str::<Value>(&response)
-                .expect("TDLib прислал невалидный json");
+        let response = self.dispatcher.register(extra, RESPONSE_TIMEOUT);
 
-            if json["@type"] == "error" {
-                futures::executor::block_on(self.error_handling(&json)).unwrap();
-            }
+        self.send_query(&query.to_string()).await.expect("Строка содержала нулебой байт");
 
-            return Some(json)
+        let json = response.await.map_err(|_| TDAppError::NoResponse)?;
+
+        if json["@type"] == "error" {
+            self.error_handling(&json).await?;
+            return Err(TDError::from_json(&json).into());
         }
 
-        None
+        Ok(json)
+    }
+
+    /// Вызов метода TDLib, ответ разбирается в тип ответа метода
+    pub async fn call<F: Function>(&self, function: &F) -> Result<F::Response, TDAppError> {
+        let response = self.request(serde_json::to_value(function)?).await?;
+        Ok(serde_json::from_value(response)?)
+    }
+
+    /// Подписка на обновления TDLib с `@type` из `types`, пустой список - все обновления.
+    /// Ответы на запросы с `@extra` в подписку не попадают
+    pub fn subscribe_updates(&self, types: &[&str]) -> UpdateReceiver {
+        self.dispatcher.subscribe(types)
     }
 
-    pub async fn skip_all_update(&self, timeout: f64) {
-        while let Some(update) = self.receive(timeout).await {
-            println!("Update ==> {update}");
+    /// Следующее обновление из подписки. `None` - передача отменена или с `last_activity`,
+    /// последнего обновления этой передачи, прошло `UPDATE_IDLE_TIMEOUT`. Обновления других
+    /// передач тоже возвращаются, поэтому `last_activity` обновляет вызывающий
+    async fn next_transfer_update(
+        updates: &mut UpdateReceiver,
+        last_activity: Instant,
+        is_cancelled: impl Fn() -> bool
+    ) -> Option<TransferEvent> {
+        while last_activity.elapsed() < UPDATE_IDLE_TIMEOUT && !is_cancelled() {
+            match tokio::time::timeout(CANCEL_CHECK_INTERVAL, updates.recv()).await {
+                Ok(Ok(update)) => return Some(TransferEvent::Update(Box::new(parse_update(update)))),
+                Ok(Err(RecvError::Lagged(_))) => return Some(TransferEvent::Lagged),
+                Ok(Err(RecvError::Closed)) => return None,
+                Err(_) => continue,
+            }
         }
+
+        None
     }
 
     pub async fn account_auth(&self) -> Result<(), TDAppError> {
@@ -146,6 +188,9 @@ impl TDApp {
             "value": "true"
         }).to_string()).await.expect("Строка содержала нулебой байт");
 
+        // Ошибки запросов авторизации приходят без @extra, поэтому тоже как обновления
+        let mut updates = self.subscribe_updates(&["updateAuthorizationState", "error"]);
+
         self.send_query(
             &authentication::get_tdlib_params_request(None)
         ).await.expect("Строка содержала нулебой байт");
@@ -153,71 +198,59 @@ impl TDApp {
         let mut are_authorized = false;
 
         while !are_authorized {
-            if let Some(response) = self.receive(1.0).await {
-
-                let json = serde_json::from_str::<Value>(&response)
-                    .expect("TDLib прислал невалидный json");
+            let json = match updates.recv().await {
+                Ok(json) => json,
+                // Пропущенное изменение статуса запрашивается заново
+                Err(RecvError::Lagged(_)) => json!({
+                    "@type": "updateAuthorizationState",
+                    "authorization_state": self.request(json!({ "@type": "getAuthorizationState" })).await?
+                }),
+                Err(RecvError::Closed) => return Err(TDAppError::NoResponse),
+            };
 
-                if json["@type"] == "error" {
-                    self.error_handling(&json).await?;
-                }
+            if json["@type"] == "error" {
+                self.error_handling(&json).await?;
+                continue
+            }
 
+            println!("AuthUpdate ===> {}\n----\n", json);
+            async_io::stdout().flush().await?;
 
-                let json_type = json["@type"]
-                    .as_str()
-                    .expect("TDLib прислал невалидный json");
+            let Update::AuthorizationState { authorization_state } = parse_update(json.clone()) else {
+                continue
+            };
 
-                if json_type != "updateAuthorizationState" {
+            match authorization_state {
 
-                    async_io::stdout().write_all(format!("Update ===> {}\n----\n", json).as_bytes()).await?;
+                AuthorizationState::WaitTdlibParameters => {
+                    println!("Sending TdlibParameters");
                     async_io::stdout().flush().await?;
-                    continue
-
-                }
-                println!("AuthUpdate ===> {}\n----\n", json);
-                async_io::stdout().flush().await?;
-
-                let authorization_state = json["authorization_state"]["@type"]
-                    .as_str()
-                    .expect("TDLib прислал невалидный json");
-
-                match authorization_state {
-
-                    "authorizationStateWaitTdlibParameters" => {
-                        println!("Sending TdlibParameters");
-                        async_io::stdout().flush().await?;
 
-                        self.send_query(
-                            &auth::get_tdlib_params_request(None)
-                        ).await.expect("Строка содержала нулебой байт");
+                    self.send_query(
+                        &auth::get_tdlib_params_request(None)
+                    ).await.expect("Строка содержала нулебой байт");
 
-                    },
+                },
 
-                    "authorizationStateWaitPhoneNumber" =>
-                        self.send_query(&auth::get_phone_number_request()).await.unwrap(),
+                AuthorizationState::WaitPhoneNumber =>
+                    self.send_query(&auth::get_phone_number_request()).await.unwrap(),
 
-                    "authorizationStateWaitCode" =>
-                        self.send_query(&auth::get_check_code_request()).await.unwrap(),
+                AuthorizationState::WaitCode =>
+                    self.send_query(&auth::get_check_code_request()).await.unwrap(),
 
-                    "authorizationStateWaitPassword" =>
-                        self.send_query(&auth::get_check_password_request()).await.unwrap(),
+                AuthorizationState::WaitPassword =>
+                    self.send_query(&auth::get_check_password_request()).await.unwrap(),
 
-                    "authorizationStateReady" => {
-                        println!("|==|==|==> Authorization is completed <==|==|==|");
-                        are_authorized = true;
-                        continue;
-                    },
+                AuthorizationState::Ready => {
+                    println!("|==|==|==> Authorization is completed <==|==|==|");
+                    are_authorized = true;
+                    continue;
+                },
 
-                    "authorizationStateClosed" => println!("Обновление статуса авторизации: {}", json),
-                    "authorizationStateClosing" => println!("Обновление статуса авторизации: {}", json),
-                    "authorizationStateLoggingOut" => {
-                        println!("|==|==|==> Logging out <==|==|==|")
-                    },
-                    "authorizationStateWaitEncryptionKey" => println!("Обновление статуса авторизации: {}", json),
-                    "authorizationStateWaitOtherDeviceConfirmation" => println!("Обновление статуса авторизации: {}", json),
-                    "authorizationStateWaitRegistration" => println!("Обновление статуса авторизации: {}", json),
-                    _ => println!("Другие обновления авторизации: {}", authorization_state)
-                }
+                AuthorizationState::LoggingOut => {
+                    println!("|==|==|==> Logging out <==|==|==|")
+                },
+                _ => println!("Обновление статуса авторизации: {}", json),
             }
         }
 
@@ -237,7 +270,16 @@ impl TDApp {
                 self.send_query(&auth::get_check_code_request()).await.unwrap(),
             "PASSWORD_HASH_INVALID" =>
                 self.send_query(&auth::get_check_password_request()).await.unwrap(),
-            _ => println!("Unsupported telegram error")
+            _ => {
+                let error = TDError::from_json(json);
+
+                match error.kind() {
+                    TDErrorKind::FloodWait(wait) =>
+                        println!("Telegram ограничил частоту запросов, повтор через {} с", wait.as_secs()),
+                    TDErrorKind::Retryable => println!("Временная ошибка Telegram: {}", error),
+                    TDErrorKind::Fatal => println!("Ошибка Telegram: {}", error),
+                }
+            }
         }
 
         let mut log_file = self.error_log_file.lock().await;
@@ -249,234 +291,305 @@ impl TDApp {
         return Ok(())
     }
 
-    pub async fn get_me(&self) -> Value {
-        self.send_query(&json!({
-            "@type": "getMe"
-        }).to_string()).await.unwrap();
+    pub async fn get_me(&self) -> Result<api::User, TDAppError> {
+        self.call(&api::GetMe {}).await
+    }
 
-        while let Some(json_response) = self.next_update_json() {
-            if json_response["@type"].as_str().unwrap() == "user" {
-                return json_response
-            }
-        }
-        Value::Null
+    pub async fn create_chat(&self) -> Result<api::Chat, TDAppError> {
+        self.call(&api::CreateNewSupergroupChat {
+            title: "TelegramDrive".to_owned()
+        }).await
     }
 
-    pub async fn create_chat(&self) -> Value {
+    pub async fn get_chat(&self, chat_id: i64) -> Result<api::Chat, TDAppError> {
+        let chat = self.call(&api::GetChat { chat_id }).await?;
+        println!("chat: {:?}\n", chat);
+        Ok(chat)
+    }
 
-        let my_acc = self.get_me().await;
+    /// Информация о файле, в том числе о его локальной копии
+    pub async fn get_file(&self, file_id: i64) -> Result<api::File, TDAppError> {
+        self.call(&api::GetFile { file_id }).await
+    }
 
-        self.send_query(&dbg!(json!({
-            "@type": "createNewSupergroupChat",
-            "title": "TelegramDrive"
-        }).to_string())).await.unwrap();
+    pub async fn load_all_messages(&self, chat_id: i64) -> Vec<api::Message> {
+        let mut chat_all_messages = vec![];
+        let mut from_message_id = 0;
 
         loop {
-            if let Some(response) = self.receive(1.0).await {
-
-                let json = serde_json::from_str::<Value>(&response)
-                    .expect("TDLib прислал невалидный json");
+            let history = match self.call(&api::GetChatHistory {
+                chat_id,
+                from_message_id,
+                offset: 0,
+                limit: 100,
+                only_local: false,
+            }).await {
+                Ok(history) => history,
+                Err(e) => {
+                    println!("Не удалось загрузить сообщения: {:?}", e);
+                    return chat_all_messages
+                }
+            };
 
-                if json["@type"] == "error" { self.error_handling(&json).await.unwrap(); }
+            let Some(last_message) = history.messages.iter().flatten().next_back() else {
+                println!("Сообщений больше нет: {:?}", history);
+                return chat_all_messages
+            };
 
-                if json["@type"].as_str().unwrap() == "chat" {
-                    return json;
-                }
-            }
+            from_message_id = last_message.id;
+            chat_all_messages.extend(history.messages.into_iter().flatten());
         }
     }
 
-    pub async fn get_chat(&self, chat_id: i64) -> Value {
-        self.send_query(&json!({
-            "@type": "getChat",
-            "chat_id": chat_id
-        }).to_string()).await.unwrap();
-
-        loop {
-            if let Some(response) = self.receive(1.0).await {
-
-                let json = serde_json::from_str::<Value>(&response)
-                    .expect("TDLib прислал невалидный json");
+    pub async fn get_message(&self, message_id: i64, chat_id: i64) -> Result<api::Message, TDAppError> {
+        self.call(&api::GetMessage { chat_id, message_id }).await
+    }
 
-                if json["@type"] == "error" {
-                    self.error_handling(&json).await.unwrap();
-                }
+    /// Отправка файла, возвращает отправленное сообщение
+    pub async fn upload_file(&self, file_path: &Path, chat_id: i64) -> Result<api::Message, TDAppError> {
+        self.upload_file_with_progress(file_path, chat_id, |_, _| {}, || false).await
+    }
 
-                if json["@type"] == "chat" && json["id"].as_i64().unwrap() == chat_id {
-                    println!("chat: {}\n", json);
-                    return json
-                } else {
-                    println!("Update: {}\n", json);
+    /// Отправка файла с отчетом о прогрессе: `on_progress(загружено байт, размер файла)`.
+    /// При отмене через `is_cancelled` отправляемое сообщение удаляется, что останавливает
+    /// загрузку в TDLib. Сообщение, которое не удалось отправить, тоже удаляется,
+    /// чтобы повтор не оставлял дублей
+    pub async fn upload_file_with_progress(
+        &self,
+        file_path: &Path,
+        chat_id: i64,
+        on_progress: impl Fn(u64, u64),
+        is_cancelled: impl Fn() -> bool
+    ) -> Result<api::Message, TDAppError> {
+
+        // Подписка до запроса, чтобы не пропустить обновления отправки
+        let mut updates = self.subscribe_updates(&["updateFile", "updateMessageSendSucceeded", "updateMessageSendFailed"]);
+
+        // Ответ на sendMessage - еще не отправленное сообщение
+        let pending_message = match self.call(&api::SendMessage {
+            chat_id,
+            input_message_content: api::InputMessageContent::InputMessageDocument {
+                document: api::InputFile::InputFileLocal {
+                    path: file_path.display().to_string()
                 }
             }
+        }).await {
+            // Сообщение могло быть создано, повтор sendMessage оставил бы дубль
+            Err(TDAppError::NoResponse) => self
+                .find_sent_message(chat_id, file_path)
+                .await?
+                .ok_or(TDAppError::Unconfirmed)?,
+            result => result?,
+        };
+
+        if pending_message.sending_state.is_none() {
+            return Ok(pending_message);
         }
-    }
-
-    pub async fn load_all_messages(&self, chat_id: i64) -> Vec<Value> {
-        self.send_query(&json!({
-            "@type": "getChatHistory",
-            "chat_id": chat_id,
-            "limit": 20
-        }).to_string()).await.unwrap();
 
-        let mut chat_all_messages = vec![];
+        println!("Запрос на отправку файла отправлен.");
 
-        while let Some(json_update) = self.next_update_json() {
+        let file_id = pending_message.document().map(|document| document.document.id);
+        let mut last_activity = Instant::now();
 
-            if json_update["@type"] == "messages" {
+        while let Some(event) = TDApp::next_transfer_update(&mut updates, last_activity, &is_cancelled).await {
 
-                let total_count = json_update["total_count"].as_u64().unwrap();
+            let update = match event {
+                TransferEvent::Update(update) => *update,
+                TransferEvent::Lagged => match self.recheck_sending(chat_id, file_path, &pending_message).await? {
+                    Some(message) => return Ok(message),
+                    None => continue,
+                },
+            };
 
-                if total_count <= 0 {
-                    println!("Сообщений больше нет: {}", json_update);
-                    return chat_all_messages
+            match &update {
+                Update::File { file } if Some(file.id) == file_id => {
+                    last_activity = Instant::now();
+                    on_progress(file.remote.uploaded_size, file.size);
                 }
-
-                let messages = json_update["messages"].as_array().unwrap();
-                chat_all_messages.extend_from_slice(&messages);
-
-                println!("messages: {}\n", json_update);
-
-                let last_message_id =
-                    if let Some(message) = messages.last() {
-                        message["id"].as_i64().unwrap()
-                    } else {
-                        return chat_all_messages
-                    };
-
-                self.send_query(&json!({
-                        "@type": "getChatHistory",
-                        "chat_id": chat_id,
-                        "limit": 30,
-                        "from_message_id": last_message_id
-                    }).to_string()).await.unwrap();
-
-            } else {
-                //println!("Update: {}\n", json);
+                Update::MessageSendSucceeded { message, old_message_id } if *old_message_id == pending_message.id => {
+                    println!("FULFILE: {:?}\n", message);
+                    return Ok(message.clone())
+                }
+                Update::MessageSendFailed { message, old_message_id, .. } if *old_message_id == pending_message.id => {
+                    self.delete_message(chat_id, &[message.id]).await?;
+                    return Err(update.send_error().unwrap().into());
+                }
+                _ => {}
             }
         }
 
-        vec![]
-    }
-
-    pub async fn get_message(&self, message_id: i64, chat_id: i64) -> Value {
-
-        self.send_query(&json!({
-            "@type": "getMessage",
-            "chat_id": chat_id,
-            "message_id": message_id
-        }).to_string()).await.unwrap();
-
-        loop {
-            if let Some(response) = self.receive(1.0).await {
-
-                let json = serde_json::from_str::<Value>(&response)
-                    .expect("TDLib прислал невалидный json");
-
-                if json["@type"] == "error" {
-                    self.error_handling(&json).await.unwrap();
-                }
-
-                if json["@type"] == "message" {
+        // Без удаления зависшего сообщения повтор отправки оставил бы дубль
+        if let Err(e) = self.delete_message(chat_id, &[pending_message.id]).await {
+            println!("Не удалось удалить неотправленное сообщение {}: {:?}", pending_message.id, e);
+            return Err(TDAppError::Unconfirmed);
+        }
 
-                    if json["chat_id"] == chat_id && json["id"] == message_id {
-                        return json
-                    }
-                }
-            }
+        if is_cancelled() {
+            Err(TDAppError::Cancelled)
+        } else {
+            Err(TDAppError::NoResponse)
         }
     }
 
-    pub async fn upload_file(&self, file_path: &Path, chat_id: i64) -> (i64, Value) {
+    /// Проверка отправки после потери обновлений: отправленное сообщение или `None`,
+    /// если отправка еще идет. Сообщение, которое не удалось отправить, удаляется
+    async fn recheck_sending(
+        &self,
+        chat_id: i64,
+        file_path: &Path,
+        pending_message: &api::Message
+    ) -> Result<Option<api::Message>, TDAppError> {
+
+        // После отправки сообщение получает новый id, по старому его уже нет
+        let message = match self.get_message(pending_message.id, chat_id).await {
+            Ok(message) => Some(message),
+            Err(TDAppError::TDLib(_)) => self.find_sent_message(chat_id, file_path).await?,
+            Err(e) => return Err(e),
+        };
+
+        match message.as_ref().map(|message| &message.sending_state) {
+            Some(None) => Ok(message),
+            Some(Some(api::MessageSendingState::Pending {})) => Ok(None),
+            Some(Some(api::MessageSendingState::Failed {})) => {
+                // Ошибка отправки пришла в потерянном обновлении, после удаления отправку можно повторить
+                self.delete_message(chat_id, &[message.unwrap().id]).await?;
+                Err(TDAppError::NoResponse)
+            },
+            // Сообщение пропало, повтор мог бы оставить дубль
+            None => Err(TDAppError::Unconfirmed),
+        }
+    }
 
-        self.send_query(&json!({
-                    "@type": "sendMessage",
-                    "chat_id": chat_id,
-                    "input_message_content": {
-                        "@type": "inputMessageDocument",
-                        "document": {
-                            "@type": "inputFileLocal",
-                            "path": dbg!(file_path.display().to_string())
-                        }
-                    }
-                }).to_string()).await.unwrap();
+    /// Поиск сообщения с файлом `file_path` среди последних сообщений чата, в том числе
+    /// еще не отправленных. Нужен, когда sendMessage остался без ответа
+    async fn find_sent_message(&self, chat_id: i64, file_path: &Path) -> Result<Option<api::Message>, TDAppError> {
+        let history = self.call(&api::GetChatHistory {
+            chat_id,
+            from_message_id: 0,
+            offset: 0,
+            limit: 50,
+            only_local: true,
+        }).await?;
+
+        // TDLib может хранить абсолютный путь вместо переданного
+        let paths = [
+            Some(file_path.to_path_buf()),
+            std::fs::canonicalize(file_path).ok(),
+        ];
+
+        Ok(history.messages
+            .into_iter()
+            .flatten()
+            .find(|message| message
+                .document()
+                .is_some_and(|document| paths.contains(&Some(PathBuf::from(&document.document.local.path))))
+            ))
+    }
 
+    pub async fn download_file(&self, file_id: i64) -> Result<api::File, TDAppError> {
+        self.download_file_from(file_id, 0, |_, _| {}, || false).await
+    }
 
-        // Возможны проблемы с парсингом json
-        // Сделать проверку LocalFile.path == file_path
+    /// Скачивание файла начиная с `offset` байт, уже скачанное начало TDLib не перекачивает.
+    /// `on_progress(скачано байт, размер файла)` вызывается по мере скачивания. При отмене
+    /// через `is_cancelled` скачивание останавливается, а недокачанный файл удаляется
+    pub async fn download_file_from(
+        &self,
+        file_id: i64,
+        offset: u64,
+        on_progress: impl Fn(u64, u64),
+        is_cancelled: impl Fn() -> bool
+    ) -> Result<api::File, TDAppError> {
+
+        let mut updates = self.subscribe_updates(&["updateFile"]);
+
+        let file = self.call(&api::DownloadFile {
+            file_id,
+            priority: 1,
+            offset,
+            limit: 0,
+            synchronous: false,
+        }).await?;
+
+        if file.local.is_downloading_completed {
+            return Ok(file);
+        }
 
-        println!("Запрос на отправку файла отправлен.");
+        let mut last_activity = Instant::now();
 
-        while let Some(json_update) = self.next_update_json() {
+        while let Some(event) = TDApp::next_transfer_update(&mut updates, last_activity, &is_cancelled).await {
 
-            match json_update["@type"].as_str().unwrap() {
-                "updateFile" => {
-                    println!("FileUpdates: {}\n", json_update);
-                }
-                "updateMessageSendSucceeded" => {
+            let file = match event {
+                TransferEvent::Update(update) => match *update {
+                    Update::File { file } => file,
+                    _ => continue,
+                },
+                // Обновление о завершении могло потеряться
+                TransferEvent::Lagged => self.get_file(file_id).await?,
+            };
 
-                    //let file_id = json_update["message"]["content"]["document"]["id"].as_i64().unwrap();
+            if file.id != file_id {
+                continue;
+            }
 
-                    println!("FULFILE: {}\n", json_update);
-                    return (2, json_update)
-                }
+            last_activity = Instant::now();
+            on_progress(file.local.downloaded_size, file.expected_size);
 
-                _ => {}
+            if file.local.is_downloading_completed {
+                return Ok(file);
             }
         }
-        panic!("Не удалось загрузить файл")
-    }
-
-    pub async fn download_file(&self, file_id: i64) -> Result<Value, ()> {
-
-        self.send_query(&json!({
-            "@type": "downloadFile",
-            "file_id": file_id,
-            "priority": 1
-        }).to_string()).await.unwrap();
 
-        let mut download_file_expected_size = 0;
+        if is_cancelled() {
+            self.cancel_download_file(file_id).await?;
+            return Err(TDAppError::Cancelled);
+        }
 
-        while let Some(json_update) = self.next_update_json() {
+        Err(TDAppError::NoResponse)
+    }
 
-            match json_update["@type"].as_str().unwrap() {
+    /// Остановка скачивания и удаление недокачанной локальной копии файла
+    pub async fn cancel_download_file(&self, file_id: i64) -> Result<(), TDAppError> {
+        self.call(&api::CancelDownloadFile { file_id, only_if_pending: false }).await?;
+        self.call(&api::DeleteFile { file_id }).await?;
 
-                "file" => {
-                    if json_update["id"] == file_id {
-                        download_file_expected_size = json_update["expected_size"].as_u64().unwrap();
-                    }
-                    if json_update["local"]["is_downloading_completed"].as_bool().unwrap() {
-                        return Ok(json_update);
-                    }
+        Ok(())
+    }
 
-                    println!("{}", json_update);
-                },
+    /// Поиск id чатов по названию среди известных чатов аккаунта
+    pub async fn search_chats(&self, query: &str) -> Vec<i64> {
+        self.call(&api::SearchChats { query: query.to_owned(), limit: 50 })
+            .await
+            .map(|chats| chats.chat_ids)
+            .unwrap_or_default()
+    }
 
-                "updateFile" => {
-                    println!("FileUpdates: {}\n", json_update);
+    pub async fn pin_chat_message(&self, chat_id: i64, message_id: i64) -> Result<(), TDAppError> {
+        self.call(&api::PinChatMessage {
+            chat_id,
+            message_id,
+            disable_notification: true,
+            only_for_self: false,
+        }).await?;
 
-                    if
-                        json_update["file"]["id"] == file_id &&
-                        json_update["file"]["local"]["is_downloading_completed"].as_bool().unwrap() &&
-                        json_update["file"]["local"]["downloaded_size"] == download_file_expected_size
-                    {
-                        return Ok(json_update);
-                    }
-                }
-                _ => {}
-            }
-        }
-        Err(())
+        Ok(())
     }
 
-    pub async fn delete_message(&self, chat_id: i64, vec_message_id: &[i64]) -> Result<(), ()> {
-        self.send_query(&json!({
-            "@type": "deleteMessages",
-            "chat_id": chat_id,
-            "message_ids": vec_message_id,
-            "revoke": true
-        }).to_string()).await.unwrap();
+    pub async fn delete_message(&self, chat_id: i64, vec_message_id: &[i64]) -> Result<(), TDAppError> {
+        self.call(&api::DeleteMessages {
+            chat_id,
+            message_ids: vec_message_id.to_vec(),
+            revoke: true,
+        }).await?;
 
         Ok(())
     }
 }
+
+/// Разбор обновления, обновления, которые не удалось разобрать, пропускаются
+fn parse_update(update: Value) -> Update {
+    serde_json::from_value(update).unwrap_or_else(|e| {
+        println!("Не удалось разобрать обновление TDLib: {}", e);
+        Update::Unsupported
+    })
+}
@@ -0,0 +1,84 @@
+use std::future::Future;
+use std::time::Duration;
+
+use crate::error::{TDAppError, TDErrorKind};
+
+/// Шаг, с которым во время паузы между попытками проверяется отмена
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Повтор запросов, завершившихся повторяемой ошибкой, с экспоненциальной паузой
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Кол-во попыток вместе с первой
+    pub max_attempts: u32,
+    /// Пауза после первой неудачной попытки, дальше она удваивается
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+
+    /// Пауза после неудачной попытки `attempt` (с 1), `None` - повторять нельзя.
+    /// Ожидание, которое требует сервер во FLOOD_WAIT, не сокращается до `max_delay`
+    pub fn delay(&self, attempt: u32, error: &TDAppError) -> Option<Duration> {
+        let backoff = self.base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+
+        match error.kind() {
+            TDErrorKind::Fatal => None,
+            _ if attempt >= self.max_attempts => None,
+            TDErrorKind::FloodWait(wait) => Some(wait.max(backoff)),
+            TDErrorKind::Retryable => Some(backoff),
+        }
+    }
+
+    /// Выполнение `operation` с повторами. Отмена через `is_cancelled` прерывает паузу
+    /// между попытками, после исчерпания попыток возвращается `RetriesExhausted`
+    pub async fn run<T, F, Fut>(&self, is_cancelled: impl Fn() -> bool, mut operation: F) -> Result<T, TDAppError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, TDAppError>>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+
+            let error = match operation().await {
+                Ok(result) => return Ok(result),
+                Err(error) => error,
+            };
+
+            let Some(delay) = self.delay(attempt, &error) else {
+                return match error.kind() {
+                    TDErrorKind::Fatal => Err(error),
+                    _ => Err(TDAppError::RetriesExhausted { attempts: attempt, last: Box::new(error) }),
+                };
+            };
+
+            println!("Попытка {} не удалась: {:?}, повтор через {} с", attempt, error, delay.as_secs_f64());
+
+            let mut waited = Duration::ZERO;
+            while waited < delay {
+                if is_cancelled() {
+                    return Err(TDAppError::Cancelled);
+                }
+
+                let step = CANCEL_CHECK_INTERVAL.min(delay - waited);
+                tokio::time::sleep(step).await;
+                waited += step;
+            }
+        }
+    }
+}
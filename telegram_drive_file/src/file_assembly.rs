@@ -1,7 +1,7 @@
 use std::{
     fs::{self, File},
     io::{self, Read, Write, BufReader, Error},
-    path::{self, PathBuf},
+    path::{self, Path, PathBuf},
     borrow::Cow
 };
 use std::string::FromUtf8Error;
@@ -17,8 +17,26 @@ pub struct FilePartDecode {
 }
 
 
-struct MetaFile {
+/// Содержимое сборочного файла
+#[derive(Debug, Clone)]
+pub struct MetaFile {
+    pub source_filename: String,
+    pub source_format: String,
+    pub parts_uuid: String,
+    pub parts_hashes: Vec<Vec<u8>>,
+}
+
+impl MetaFile {
+    /// Имя файла части с номером `part_number` (нумерация с 1)
+    pub fn part_file_name(&self, part_number: usize) -> String {
+        format!("{}_{}.part", self.parts_uuid, part_number)
+    }
 
+    pub fn parts_name(&self) -> Vec<String> {
+        (1..=self.parts_hashes.len())
+            .map(|part_number| self.part_file_name(part_number))
+            .collect()
+    }
 }
 
 #[derive(Debug)]
@@ -26,8 +44,11 @@ pub enum DecodeErrors {
     IOError(::std::io::Error),
     FromUtf8Error(::std::string::FromUtf8Error),
     IterationError,
+    PartsCountError,
     DecodePart(usize),
     PathParseError,
+    /// Сборка отменена, недособранный файл удален
+    Cancelled,
 }
 
 impl From<std::io::Error> for DecodeErrors {
@@ -108,70 +129,83 @@ pub fn decode_file(metafile_path: &PathBuf, path_for_save: PathBuf) -> Result<()
         return Err(DecodeErrors::PathParseError)
     }
 
-    let mut parts_folder = metafile_path.clone();
-    parts_folder.pop();
+    let metafile = match read_metafile(metafile_path) {
+        Err(DecodeErrors::PartsCountError) => {
+            println!(
+                "\n!!!!!!!!!!!!!\nОшибка кол-ва частей в сборочном файле.\n!!!!!!!!!!!!!\n"
+            );
+            std::process::exit(2)
+        },
+        res => res?
+    };
 
-    let mut metafile_bytes = vec![];
-    File::open(&metafile_path)?.read_to_end(&mut metafile_bytes)?;
+    let output_path = format!(
+        "{}{}.{}",
+        path_for_save.display(),
+        metafile.source_filename,
+        metafile.source_format
+    );
 
-    let mut metafile_bytes_iter = metafile_bytes.into_iter();
+    decode_file_to(metafile_path, Path::new(&output_path)).map(|_| ())
+}
 
-    let source_filename = decode_str::<u8>(&mut metafile_bytes_iter)?;
-    let source_format = decode_str::<u8>(&mut metafile_bytes_iter)?;
-    let parts_uuid = decode_str::<u8>(&mut metafile_bytes_iter)?;
+/// Сборка файла по сборочному файлу в `output_path`.
+///
+/// Части ищутся рядом со сборочным файлом. Файл сначала собирается во временный
+/// `output_path.partial`, поэтому при ошибке по пути `output_path` не остается
+/// недособранного файла.
+pub fn decode_file_to(metafile_path: &Path, output_path: &Path) -> Result<MetaFile, DecodeErrors> {
+    decode_file_to_with_progress(metafile_path, output_path, |_, _| {}, || false)
+}
 
-    let mut output_file = File::create(
-        format!("{}{}.{}",path_for_save.display(), source_filename, source_format)
-    )?;
+/// Сборка файла с отчетом о прогрессе: `on_progress(записано байт, размер файла)`
+/// вызывается после каждой части. Перед каждой частью проверяется `is_cancelled`
+pub fn decode_file_to_with_progress(
+    metafile_path: &Path,
+    output_path: &Path,
+    mut on_progress: impl FnMut(u64, u64),
+    is_cancelled: impl Fn() -> bool
+) -> Result<MetaFile, DecodeErrors> {
 
-    // Остаточные байты представляют из себя массив хешей,
-    // где кол-во хешей берется из контекста
-    let count_parts = <usize>::decode_from_iter(&mut metafile_bytes_iter)?;
-    let parts_hashes = metafile_bytes_iter.collect::<Vec<u8>>();
+    let parts_folder = metafile_path.parent().ok_or(DecodeErrors::PathParseError)?;
+    let metafile = read_metafile(metafile_path)?;
 
-    if count_parts != parts_hashes.len()/16 {
-        println!(
-            "\n!!!!!!!!!!!!!\nОшибка кол-ва частей в сборочном файле.\n!!!!!!!!!!!!!\n"
-        );
-        std::process::exit(2)
-    }
+    let mut partial_path = output_path.as_os_str().to_owned();
+    partial_path.push(".partial");
+    let partial_path = PathBuf::from(partial_path);
 
-    parts_hashes
-        .chunks(16)
-        .enumerate()
-        .map(|(part_ind, part_hash)| {
-            let mut part = decode_part(
-                &parts_folder,
-                &parts_uuid,
-                part_ind+1,
-                part_hash
-            );
+    let result = (|| {
+        let mut output_file = File::create(&partial_path)?;
+
+        // Размер файла - сумма размеров частей без 16 байт хеша в начале каждой
+        let file_size = (1..=metafile.parts_hashes.len())
+            .map(|part_number| fs::metadata(parts_folder.join(metafile.part_file_name(part_number))))
+            .map(|part_metadata| part_metadata.map(|part_metadata| part_metadata.len().saturating_sub(16)).unwrap_or_default())
+            .sum();
 
-            let mut bytes_part = vec![];
-            // При большой чатси уходит много ОЗУ
-
-            let mut parts_folder_cl = parts_folder.clone();
-            parts_folder_cl.push(part.part_file_name);
-
-            part.file.read_to_end(&mut bytes_part)?;
-
-            output_file.write(&bytes_part)?;
-            // Создание евента для frontend
-            Ok::<(), DecodeErrors>(())
-        })
-        .all(|res|
-            if res.is_ok() {
-                true
-            } else {
-                println!(
-                    "\n!!!!!!!!!!!!!\nНекорректное декодирование части с ошибкой {:?}\n!!!!!!!!!!!!!\n",
-                    res.err().unwrap()
-                );
-                std::process::exit(2)
+        let mut done_size = 0;
+
+        for part_number in 1..=metafile.parts_hashes.len() {
+            if is_cancelled() {
+                return Err(DecodeErrors::Cancelled);
             }
-        );
 
-    Ok(())
+            let mut part_file = open_part(parts_folder, &metafile, part_number)?;
+            done_size += io::copy(&mut part_file, &mut output_file)?;
+            on_progress(done_size, file_size);
+        }
+
+        output_file.flush()?;
+        fs::rename(&partial_path, output_path)?;
+
+        Ok::<(), DecodeErrors>(())
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&partial_path);
+    }
+
+    result.map(|_| metafile)
 }
 
 fn decode_input_file_extension(src: &mut impl Read) -> String {
@@ -189,50 +223,52 @@ fn decode_input_file_extension(src: &mut impl Read) -> String {
     String::from_utf8(extension_bytes).unwrap()
 }
 
-fn decode_metafile() -> MetaFile {
-    MetaFile {
+/// Чтение сборочного файла без сборки исходного файла
+pub fn read_metafile(metafile_path: &Path) -> Result<MetaFile, DecodeErrors> {
+
+    let mut metafile_bytes = vec![];
+    File::open(metafile_path)?.read_to_end(&mut metafile_bytes)?;
+
+    let mut metafile_bytes_iter = metafile_bytes.into_iter();
+
+    let source_filename = decode_str::<u8>(&mut metafile_bytes_iter)?;
+    let source_format = decode_str::<u8>(&mut metafile_bytes_iter)?;
+    let parts_uuid = decode_str::<u8>(&mut metafile_bytes_iter)?;
 
+    // Остаточные байты представляют из себя массив хешей,
+    // где кол-во хешей берется из контекста
+    let count_parts = <usize>::decode_from_iter(&mut metafile_bytes_iter)?;
+    let parts_hashes = metafile_bytes_iter.collect::<Vec<u8>>();
+
+    if parts_hashes.len() % 16 != 0 || count_parts != parts_hashes.len()/16 {
+        return Err(DecodeErrors::PartsCountError)
     }
+
+    Ok(MetaFile {
+        source_filename,
+        source_format,
+        parts_uuid,
+        parts_hashes: parts_hashes.chunks(16).map(|hash| hash.to_vec()).collect(),
+    })
 }
 
-fn decode_part(parts_folder: &PathBuf, part_uuid: &str, part_number: usize, part_hash: &[u8]) -> FilePartDecode {
+/// Открытие части с проверкой ее хеша, файл возвращается сдвинутым на начало данных
+fn open_part(parts_folder: &Path, metafile: &MetaFile, part_number: usize) -> Result<File, DecodeErrors> {
 
-    let part_file_name = format!("{}_{}.part", part_uuid, part_number);
-    let mut part_path = parts_folder.clone();
-    part_path.push(part_file_name.clone());
-    println!("ДЕЮАГ ИНФА: {:?}", &part_path);
+    let part_path = parts_folder.join(metafile.part_file_name(part_number));
 
-    let mut part_file = if let Ok(f) = File::open(&part_path) {
-        f
-    } else {
-        println!(
-            "\n!!!!!!!!!!!!!\nНеудается найти {} часть в текущей папке.\n!!!!!!!!!!!!!\n",
-            part_number
-        );
-        std::process::exit(2)
-    };
+    let mut part_file = File::open(&part_path)
+        .map_err(|_| DecodeErrors::DecodePart(part_number))?;
 
-    let mut tmp_hash = [0_u8;16];
-    part_file.read(&mut tmp_hash).unwrap();
+    let mut part_hash = [0_u8;16];
+    part_file
+        .read_exact(&mut part_hash)
+        .map_err(|_| DecodeErrors::DecodePart(part_number))?;
 
-    let hash_bytes = tmp_hash
-        .into_iter()
-        .enumerate()
-        // Сравнение хеша, полученного из сброчного файла и хеша в файле
-        .filter_map(|(index, byte)| if byte == part_hash[index] {Some(byte)} else {None} )
-        .collect::<Vec<_>>();
-
-    if hash_bytes.len() != 16 {
-        println!(
-            "\n!!!!!!!!!!!!!\nХеш {} части не совпадает.\n!!!!!!!!!!!!!\n",
-            part_number
-        );
-        std::process::exit(2)
+    // Сравнение хеша, полученного из сброчного файла и хеша в файле
+    if part_hash.as_slice() != metafile.parts_hashes[part_number - 1] {
+        return Err(DecodeErrors::DecodePart(part_number));
     }
 
-    FilePartDecode {
-        file: part_file,
-        hash_bytes,
-        part_file_name,
-    }
-}
\ No newline at end of file
+    Ok(part_file)
+}
@@ -14,6 +14,8 @@ pub enum EncodeErrors {
     IOError(::std::io::Error),
     OsStringError(std::ffi::OsString),
     PathParseError,
+    /// Разбиение отменено, созданные части удалены
+    Cancelled,
 }
 
 impl From<std::io::Error> for EncodeErrors {
@@ -38,6 +40,18 @@ pub struct SeparationFile {
 }
 
 pub fn encode_file(path: &PathBuf, options: Options) -> Result<SeparationFile, EncodeErrors> {
+    encode_file_with_progress(path, options, |_, _| {}, || false)
+}
+
+/// Разбиение файла на части с отчетом о прогрессе: `on_progress(записано байт, размер файла)`
+/// вызывается после каждой части. Перед каждой частью проверяется `is_cancelled`,
+/// при отмене уже созданные части удаляются
+pub fn encode_file_with_progress(
+    path: &PathBuf,
+    options: Options,
+    mut on_progress: impl FnMut(u64, u64),
+    is_cancelled: impl Fn() -> bool
+) -> Result<SeparationFile, EncodeErrors> {
 
     if !path.is_file() {
         println!("{}", path.display());
@@ -52,6 +66,8 @@ pub fn encode_file(path: &PathBuf, options: Options) -> Result<SeparationFile, E
         return dbg!(Err(EncodeErrors::PathParseError));
     }
     let file = File::open(&path)?;
+    let file_size = file.metadata()?.len();
+    let mut done_size = 0;
 
     let mut size_part = options.part_size.unwrap_or(1_073_741_824_usize);
 
@@ -66,9 +82,10 @@ pub fn encode_file(path: &PathBuf, options: Options) -> Result<SeparationFile, E
             .ok_or(EncodeErrors::PathParseError)?
             .to_os_string()
             .into_string()?,
+        // У файла может не быть расширения, например Makefile
         file_extension:  path
             .extension()
-            .ok_or(EncodeErrors::PathParseError)?
+            .unwrap_or_default()
             .to_os_string()
             .into_string()?,
         file_len: f.capacity(),
@@ -80,10 +97,17 @@ pub fn encode_file(path: &PathBuf, options: Options) -> Result<SeparationFile, E
 
     let mut number_part = 1;
 
-    let mut parts = vec![];
+    let mut parts: Vec<FilePart> = vec![];
 
     while f.has_data_left()? {
 
+        if is_cancelled() {
+            for part in &parts {
+                let _ = fs::remove_file(format!("{}{}", path_for_save.display(), part.part_file_name));
+            }
+            return Err(EncodeErrors::Cancelled);
+        }
+
         if number_part > max_count_parts {
             println!(
                 "Файл слишком большой для размещения в {} частей",
@@ -108,6 +132,9 @@ pub fn encode_file(path: &PathBuf, options: Options) -> Result<SeparationFile, E
 
         number_part += 1;
         f.consume(buffer_bytes_len);
+
+        done_size += buffer_bytes_len as u64;
+        on_progress(done_size, file_size);
     }
 
     let metafile = encode_metafile(&composite_file, &path_for_save)?;